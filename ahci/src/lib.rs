@@ -7,60 +7,141 @@
 
 extern crate alloc;
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use eclipse_framebuffer::println;
-use pci::{pci_config_read_dword, PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_SATA};
 
 pub use types::*;
 mod types;
 
-fn start_cmd(port: &mut HbaPort) {
-    let cmd = port.read_cmd();
-    if (cmd & (1 << 4)) != 0 {
-        return;
+/// How long a port gets to settle after a reset or engine change
+const AHCI_TIMEOUT_MS: u64 = 1000;
+/// COMRESET has to be held for at least 1 ms
+const AHCI_COMRESET_HOLD_MS: u64 = 1;
+/// Used instead of the clock until `ahci_set_clock` has been called
+const AHCI_FALLBACK_SPINS: usize = 1_000_000;
+
+/// Millisecond counter provided by the kernel
+static mut AHCI_CLOCK: Option<fn() -> u64> = None;
+
+/// Gives the driver a millisecond clock so its waits are in wall clock time.
+pub fn ahci_set_clock(get_ms: fn() -> u64) {
+    unsafe {
+        AHCI_CLOCK = Some(get_ms);
     }
-    while (cmd & (1 << 15)) != 0 {
-        // Wait until CR (bit15) is cleared
+}
+
+/// Spins until `done` returns true, returns false if `timeout_ms` ran out first.
+fn ahci_wait_until(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    match unsafe { AHCI_CLOCK } {
+        Some(get_ms) => {
+            let start = get_ms();
+            while !done() {
+                if get_ms().wrapping_sub(start) > timeout_ms {
+                    return false;
+                }
+                core::hint::spin_loop();
+            }
+            true
+        }
+        None => {
+            for _ in 0..AHCI_FALLBACK_SPINS {
+                if done() {
+                    return true;
+                }
+                core::hint::spin_loop();
+            }
+            false
+        }
     }
-    port.write_cmd(cmd | (1 << 4));
+}
+
+fn start_cmd(port: &mut HbaPort) {
+    // Wait until CR (bit15) is cleared
+    ahci_wait_until(AHCI_TIMEOUT_MS, || (port.read_cmd() & HBA_PORT_CMD_CR) == 0);
+    let cmd = port.read_cmd();
+    port.write_cmd(cmd | HBA_PORT_CMD_FRE);
+    port.write_cmd(cmd | HBA_PORT_CMD_FRE | HBA_PORT_CMD_ST);
 }
 
 fn stop_cmd(port: &mut HbaPort) {
     let cmd = port.read_cmd();
-    port.write_cmd(cmd & !(1 << 4));
-    while (cmd & (1 << 15)) != 0 {
-        // Wait until CR (bit15) is cleared
-    }
-    port.write_cmd(cmd & !(1 << 0));
-    while (cmd & (1 << 14)) != 0 {
-        // Wait until FR (bit14) is cleared
+    port.write_cmd(cmd & !HBA_PORT_CMD_ST);
+    // Wait until CR (bit15) is cleared
+    ahci_wait_until(AHCI_TIMEOUT_MS, || (port.read_cmd() & HBA_PORT_CMD_CR) == 0);
+    let cmd = port.read_cmd();
+    port.write_cmd(cmd & !HBA_PORT_CMD_FRE);
+    // Wait until FR (bit14) is cleared
+    ahci_wait_until(AHCI_TIMEOUT_MS, || (port.read_cmd() & HBA_PORT_CMD_FR) == 0);
+}
+
+// Layout of the DMA region, offsets from its physical base
+const DMA_CMD_LIST_OFFSET: u64 = 0;
+const DMA_FIS_OFFSET: u64 = 32 << 10;
+const DMA_CMD_TABLE_OFFSET: u64 = 40 << 10;
+const DMA_SECTOR_BUFFER_OFFSET: u64 = DMA_CMD_TABLE_OFFSET + (32 << 13);
+
+/// Bytes of DMA memory `ahci_init_from_abar` needs: 32 command lists of 1 KiB,
+/// 32 received FIS areas of 256 bytes, 32 * 32 command tables of 256 bytes
+/// and a 512-byte sector buffer per port
+pub const AHCI_DMA_SIZE: usize = DMA_SECTOR_BUFFER_OFFSET as usize + (32 << 9);
+
+/// Added to a physical DMA address to reach it from the kernel
+static DMA_VIRT_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Physical base of the DMA region, 0 before `ahci_init_from_abar`
+static DMA_PHYS_BASE: AtomicU64 = AtomicU64::new(0);
+/// Kernel address of the controller registers, 0 before `ahci_init_from_abar`
+static ABAR_VIRT: AtomicU64 = AtomicU64::new(0);
+
+/// What IDENTIFY DEVICE reported for a SATA drive
+#[derive(Debug, Clone, Copy)]
+pub struct AhciIdentify {
+    /// Addressable sectors, from the LBA48 count when the drive supports it
+    pub sectors: u64,
+    pub model: [u8; 41],
+}
+
+impl AhciIdentify {
+    /// The drive's model name with the space and null padding trimmed.
+    pub fn model_str(&self) -> &str {
+        let end = self.model.iter().position(|&b| b == 0).unwrap_or(self.model.len());
+        core::str::from_utf8(&self.model[..end]).unwrap_or("Unknown").trim_end()
     }
 }
 
+/// IDENTIFY results filled in by `probe_ports`, indexed by port number
+static mut AHCI_IDENTIFY: [Option<AhciIdentify>; 32] = [None; 32];
+
+/// Kernel pointer for a physical address inside the DMA region.
+fn dma_ptr<T>(phys: u64) -> *mut T {
+    phys.wrapping_add(DMA_VIRT_OFFSET.load(Ordering::Relaxed)) as *mut T
+}
+
 fn rebase_port(port: &mut HbaPort, portno: u32, base: u64) {
     stop_cmd(port);
     
-    port.clb = base + ((portno as u64) << 10);
-    unsafe { core::ptr::write_bytes(port.clb as *mut u8, 0, 1024); }
+    port.clb = base + DMA_CMD_LIST_OFFSET + ((portno as u64) << 10);
+    unsafe { core::ptr::write_bytes(dma_ptr::<u8>(port.clb), 0, 1024); }
     
-    port.fb = base + (32 << 10) + ((portno as u64) << 8);
-    unsafe { core::ptr::write_bytes(port.fb as *mut u8, 0, 256); }
+    port.fb = base + DMA_FIS_OFFSET + ((portno as u64) << 8);
+    unsafe { core::ptr::write_bytes(dma_ptr::<u8>(port.fb), 0, 256); }
     
 
-    let cmdheader = port.clb as *mut HbaCmdHeader;
+    let cmdheader = dma_ptr::<HbaCmdHeader>(port.clb);
     for i in 0..32 {
         unsafe {
             (*cmdheader.add(i)).prdtl = 8;
             
-            (*cmdheader.add(i)).ctba = base + (40 << 10) + ((portno as u64) << 13) + ((i as u64) << 8);
+            (*cmdheader.add(i)).ctba = base + DMA_CMD_TABLE_OFFSET + ((portno as u64) << 13) + ((i as u64) << 8);
             
-            core::ptr::write_bytes((*cmdheader.add(i)).ctba as *mut u8, 0, 256);
+            core::ptr::write_bytes(dma_ptr::<u8>((*cmdheader.add(i)).ctba), 0, 256);
         }
     }
     
     start_cmd(port);
 }
 
-pub fn probe_ports(abar: &mut HbaMem) {
+/// Sets up every implemented port, `dma_base` is the physical base of an `AHCI_DMA_SIZE` region.
+pub fn probe_ports(abar: &mut HbaMem, dma_base: u64) {
     let pi = abar.read_pi();
     
     for i in 0..32 {
@@ -69,11 +150,18 @@ pub fn probe_ports(abar: &mut HbaMem) {
             match dt {
                 AHCI_DEV_SATA => {
                     println!("SATA drive found at port {}", i);
-                    rebase_port(&mut abar.ports[i], i as u32, 0x400000);
+                    rebase_port(&mut abar.ports[i], i as u32, dma_base);
+
+                    let identify = ahci_identify(&mut abar.ports[i]);
+                    match identify {
+                        Some(info) => println!("  Model: {}, {} sectors ({} MiB)", info.model_str(), info.sectors, info.sectors / 2048),
+                        None => println!("  IDENTIFY failed on port {}", i),
+                    }
+                    unsafe { AHCI_IDENTIFY[i] = identify };
                 }
                 AHCI_DEV_SATAPI => {
                     println!("SATAPI drive found at port {}", i);
-                    rebase_port(&mut abar.ports[i], i as u32, 0x400000);
+                    rebase_port(&mut abar.ports[i], i as u32, dma_base);
                 }
                 AHCI_DEV_SEMB => {
                     println!("SEMB drive found at port {}", i);
@@ -109,125 +197,286 @@ fn check_type(port: &HbaPort) -> u8 {
     }
 }
 
-pub fn find_ahci_controller() -> Option<u64> {
-    
-    println!("Scanning PCI for AHCI controller...");
-    
-    for bus in 0..=255u16 {
-        for device in 0..32u8 {
-            for function in 0..8u8 {
-                let vendor_id = pci_config_read_dword(bus as u8, device, function, 0x00) & 0xFFFF;
-                
-                if vendor_id == 0xFFFF || vendor_id == 0x0000 {
-                    continue;
-                }
-                
-                let class_reg = pci_config_read_dword(bus as u8, device, function, 0x08);
-                let class_code = (class_reg >> 24) & 0xFF;
-                let subclass = (class_reg >> 16) & 0xFF;
-                let prog_if = (class_reg >> 8) & 0xFF;
-                
-                if class_code == PCI_CLASS_MASS_STORAGE as u32 && 
-                   subclass == PCI_SUBCLASS_SATA as u32 && 
-                   prog_if == 0x01 {
-                    println!("Found AHCI controller at {}:{}:{}", bus, device, function);
-                    let bar5 = pci_config_read_dword(bus as u8, device, function, 0x24);
-                    let abar = (bar5 & !0xF) as u64;
-                    println!("BAR5 = 0x{:X}", abar);
-                    return Some(abar);
-                }
-            }
+/// Sets up every port of a controller whose ABAR is already mapped at `abar_virt`.
+/// Command lists, FIS areas and command tables go in the `AHCI_DMA_SIZE` bytes of physically
+/// contiguous, page aligned memory at `dma_phys`, which the kernel reaches at `dma_virt`.
+/// Returns `None` for a null ABAR or DMA region, or a controller without any implemented ports.
+///
+/// # Safety
+/// `abar_virt` must be the controller's ABAR mapped uncached for the whole `HbaMem`, and stay
+/// mapped for the life of the kernel. The DMA region must be owned by the driver from here on:
+/// nothing else may use it or free it while the controller can still access it.
+pub unsafe fn ahci_init_from_abar(abar_virt: u64, dma_phys: u64, dma_virt: u64) -> Option<&'static mut HbaMem> {
+    if abar_virt == 0 || dma_phys == 0 {
+        return None;
+    }
+    DMA_VIRT_OFFSET.store(dma_virt.wrapping_sub(dma_phys), Ordering::Relaxed);
+    DMA_PHYS_BASE.store(dma_phys, Ordering::Relaxed);
+
+    let abar = unsafe { &mut *(abar_virt as *mut HbaMem) };
+    if abar.read_pi() == 0 {
+        println!("AHCI controller has no implemented ports");
+        return None;
+    }
+
+    probe_ports(abar, dma_phys);
+    ABAR_VIRT.store(abar_virt, Ordering::Relaxed);
+    Some(abar)
+}
+
+/// Fills a Register H2D FIS for a 48-bit DMA read/write.
+/// The EXT commands take the LBA as six bytes split around the device register
+/// and a full 16-bit sector count, a count of 0 means 65536 sectors.
+fn build_rw_fis(fis: &mut [u8; 64], command: u8, lba: u64, count: u32) {
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = 0x80;
+    fis[2] = command;
+    fis[3] = 0x00;
+    fis[4] = (lba & 0xFF) as u8;
+    fis[5] = ((lba >> 8) & 0xFF) as u8;
+    fis[6] = ((lba >> 16) & 0xFF) as u8;
+    fis[7] = 0x40;
+    fis[8] = ((lba >> 24) & 0xFF) as u8;
+    fis[9] = ((lba >> 32) & 0xFF) as u8;
+    fis[10] = ((lba >> 40) & 0xFF) as u8;
+    fis[11] = 0x00;
+    fis[12] = (count & 0xFF) as u8;
+    fis[13] = ((count >> 8) & 0xFF) as u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// Every command slot on the port is in use
+    Busy,
+    /// The drive never cleared the command issue bit
+    Timeout,
+    /// The drive reported an error, holds the ATA error register
+    TaskFileError(u8),
+    /// The buffer fragments don't match the sector count or need more than 8 PRDT entries
+    InvalidBuffer,
+}
+
+/// Returns the first command slot that is neither issued (CI) nor queued (SACT).
+fn find_free_cmd_slot(port: &HbaPort) -> Option<u32> {
+    let used = port.read_sact() | port.read_ci();
+    (0..32).find(|slot| (used & (1 << slot)) == 0)
+}
+
+/// Issues the command in `slot` and waits for it to finish.
+/// The port interrupt status is cleared on every return path.
+fn ahci_issue_and_wait(port: &HbaPort, slot: u32) -> Result<(), AhciError> {
+    let port_mut = port as *const HbaPort as *mut HbaPort;
+    let slot_bit = 1 << slot;
+    unsafe {
+        (*port_mut).write_is((*port_mut).read_is());
+        // Writing 0 bits to CI has no effect, so only this slot gets issued
+        (*port_mut).write_ci(slot_bit);
+
+        // Stops early on a task file error, the check below turns that into the error
+        let finished = ahci_wait_until(AHCI_TIMEOUT_MS, || {
+            ((*port_mut).read_is() & HBA_PX_IS_TFES) != 0 || ((*port_mut).read_ci() & slot_bit) == 0
+        });
+        let mut result = if finished { Ok(()) } else { Err(AhciError::Timeout) };
+
+        let tfd = (*port_mut).read_tfd();
+        if ((*port_mut).read_is() & HBA_PX_IS_TFES) != 0 || (tfd & ATA_DEV_ERR as u32) != 0 {
+            result = Err(AhciError::TaskFileError(((tfd >> 8) & 0xFF) as u8));
         }
+
+        (*port_mut).write_is((*port_mut).read_is());
+        result
     }
-    
-    println!("No AHCI controller found");
-    None
 }
 
-pub fn ahci_read(port: &HbaPort, lba: u64, count: u32, buffer: *mut u8) -> bool {
-    let ci = port.read_ci();
-    if ci != 0 {
-        return false;
+/// Fills the PRDT from `(phys_addr, len)` fragments, splitting anything over 4 MiB.
+/// Returns the number of entries used.
+unsafe fn fill_prdt(cmdtbl: *mut HbaCmdTbl, fragments: &[(u64, u32)], total_bytes: u64) -> Result<u16, AhciError> {
+    let mut entries = 0;
+    let mut bytes = 0u64;
+
+    for &(mut addr, mut len) in fragments {
+        // The byte count has to be even, bit 0 of dbc is always set
+        if len == 0 || len % 2 != 0 {
+            return Err(AhciError::InvalidBuffer);
+        }
+
+        while len > 0 {
+            if entries >= AHCI_MAX_PRDT_ENTRIES {
+                return Err(AhciError::InvalidBuffer);
+            }
+
+            let chunk = len.min(AHCI_PRDT_MAX_BYTES);
+            unsafe {
+                (*cmdtbl).prdt_entry[entries].dba = addr;
+                (*cmdtbl).prdt_entry[entries].dbc = chunk - 1;
+            }
+
+            entries += 1;
+            addr += chunk as u64;
+            len -= chunk;
+            bytes += chunk as u64;
+        }
     }
 
-    let cmdheader = port.clb as *mut HbaCmdHeader;
+    if bytes != total_bytes {
+        return Err(AhciError::InvalidBuffer);
+    }
+
+    Ok(entries as u16)
+}
+
+/// Builds a read or write DMA command over the given fragments in `slot` and runs it.
+fn ahci_rw(port: &HbaPort, slot: u32, lba: u64, count: u32, fragments: &[(u64, u32)], write: bool) -> Result<(), AhciError> {
+    let cmdheader = unsafe { dma_ptr::<HbaCmdHeader>(port.clb).add(slot as usize) };
     unsafe {
-        (*cmdheader).prdtl = 1;
-        
-        let cmdtbl = (*cmdheader).ctba as *mut HbaCmdTbl;
+        let cmdtbl = dma_ptr::<HbaCmdTbl>((*cmdheader).ctba);
         core::ptr::write_bytes(cmdtbl as *mut u8, 0, 256);
-        
-        let fis = &mut (*cmdtbl).cfis;
-        fis[0] = 0x27;
-        fis[1] = 0x80;
-        fis[2] = 0xC8;
-        fis[3] = 0x00;
-        fis[4] = (lba & 0xFF) as u8;
-        fis[5] = ((lba >> 8) & 0xFF) as u8;
-        fis[6] = ((lba >> 16) & 0xFF) as u8;
-        fis[7] = 0xE0 | ((lba >> 24) & 0x0F) as u8;
-        fis[8] = ((lba >> 32) & 0xFF) as u8;
-        fis[9] = ((lba >> 40) & 0xFF) as u8;
-        fis[10] = ((lba >> 48) & 0xFF) as u8;
-        fis[11] = 0x00;
-        fis[12] = (count & 0xFF) as u8;
-        fis[13] = ((count >> 8) & 0xFF) as u8;
-        
-        (*cmdtbl).prdt_entry[0].dba = buffer as u64;
-        (*cmdtbl).prdt_entry[0].dbc = (count as u32 * 512) - 1;
-        
-        let port_mut = port as *const HbaPort as *mut HbaPort;
-        (*port_mut).ci = 1;
-        
-        let mut timeout = 1000000;
-        while ((*port_mut).ci & 1) != 0 && timeout > 0 {
-            timeout -= 1;
-        }
+
+        let prdtl = fill_prdt(cmdtbl, fragments, count as u64 * 512)?;
+
+        (*cmdheader).flags = if write {
+            AHCI_CMD_HEADER_FLAGS_FIS_LEN | AHCI_CMD_HEADER_FLAGS_WRITE
+        } else {
+            AHCI_CMD_HEADER_FLAGS_FIS_LEN
+        };
+        (*cmdheader).prdtl = prdtl;
+
+        let command = if write { ATA_CMD_WRITE_DMA_EX } else { ATA_CMD_READ_DMA_EX };
+        build_rw_fis(&mut (*cmdtbl).cfis, command, lba, count);
     }
-    
-    true
+
+    ahci_issue_and_wait(port, slot)
+}
+
+pub fn ahci_read(port: &HbaPort, lba: u64, count: u32, buffer: *mut u8) -> Result<(), AhciError> {
+    let bytes = count.checked_mul(512).ok_or(AhciError::InvalidBuffer)?;
+    let slot = find_free_cmd_slot(port).ok_or(AhciError::Busy)?;
+    ahci_rw(port, slot, lba, count, &[(buffer as u64, bytes)], false)
+}
+
+pub fn ahci_write(port: &HbaPort, lba: u64, count: u32, buffer: *const u8) -> Result<(), AhciError> {
+    let bytes = count.checked_mul(512).ok_or(AhciError::InvalidBuffer)?;
+    let slot = find_free_cmd_slot(port).ok_or(AhciError::Busy)?;
+    ahci_rw(port, slot, lba, count, &[(buffer as u64, bytes)], true)
+}
+
+/// Reads `count` sectors into physically scattered `(phys_addr, len)` fragments.
+/// The fragments must add up to `count * 512` bytes and fit in 8 PRDT entries of at most 4 MiB each.
+pub fn ahci_read_scatter(port: &HbaPort, lba: u64, count: u32, prdt: &[(u64, u32)]) -> Result<(), AhciError> {
+    let slot = find_free_cmd_slot(port).ok_or(AhciError::Busy)?;
+    ahci_rw(port, slot, lba, count, prdt, false)
 }
 
-pub fn ahci_write(port: &HbaPort, lba: u64, count: u32, buffer: *const u8) -> bool {
-    let ci = port.read_ci();
-    if ci != 0 {
-        return false;
+/// Physical address of the 512-byte sector buffer `rebase_port` set aside for `port`.
+fn port_sector_buffer(port: &HbaPort) -> Option<u64> {
+    let dma_base = DMA_PHYS_BASE.load(Ordering::Relaxed);
+    if dma_base == 0 || port.clb < dma_base + DMA_CMD_LIST_OFFSET {
+        return None;
+    }
+    // Each port owns 1 KiB of command list, which gives back its number
+    let portno = (port.clb - dma_base - DMA_CMD_LIST_OFFSET) >> 10;
+    if portno >= 32 {
+        return None;
     }
+    Some(dma_base + DMA_SECTOR_BUFFER_OFFSET + (portno << 9))
+}
 
-    let cmdheader = port.clb as *mut HbaCmdHeader;
+/// The port's 512-byte DMA sector buffer as `(physical, kernel pointer)`. The physical
+/// address can be handed to `ahci_read`/`ahci_write` for single sector transfers.
+/// `ahci_identify` reuses it, so copy data out before issuing another command.
+pub fn ahci_sector_buffer(port: &HbaPort) -> Option<(u64, *mut u8)> {
+    let phys = port_sector_buffer(port)?;
+    Some((phys, dma_ptr::<u8>(phys)))
+}
+
+/// Sends IDENTIFY DEVICE to a SATA drive on a port set up by `probe_ports` and decodes
+/// its size and model. Returns `None` if the command fails or the port has no DMA area.
+pub fn ahci_identify(port: &mut HbaPort) -> Option<AhciIdentify> {
+    let buffer_phys = port_sector_buffer(port)?;
+
+    let slot = find_free_cmd_slot(port)?;
+    let cmdheader = unsafe { dma_ptr::<HbaCmdHeader>(port.clb).add(slot as usize) };
     unsafe {
-        (*cmdheader).prdtl = 1;
-        
-        let cmdtbl = (*cmdheader).ctba as *mut HbaCmdTbl;
+        let cmdtbl = dma_ptr::<HbaCmdTbl>((*cmdheader).ctba);
         core::ptr::write_bytes(cmdtbl as *mut u8, 0, 256);
-        
+        core::ptr::write_bytes(dma_ptr::<u8>(buffer_phys), 0, 512);
+
+        let prdtl = fill_prdt(cmdtbl, &[(buffer_phys, 512)], 512).ok()?;
+        (*cmdheader).flags = AHCI_CMD_HEADER_FLAGS_FIS_LEN;
+        (*cmdheader).prdtl = prdtl;
+
         let fis = &mut (*cmdtbl).cfis;
-        fis[0] = 0x27;
+        fis[0] = FIS_TYPE_REG_H2D;
         fis[1] = 0x80;
-        fis[2] = 0xCA;
-        fis[3] = 0x00;
-        fis[4] = (lba & 0xFF) as u8;
-        fis[5] = ((lba >> 8) & 0xFF) as u8;
-        fis[6] = ((lba >> 16) & 0xFF) as u8;
-        fis[7] = 0xE0 | ((lba >> 24) & 0x0F) as u8;
-        fis[8] = ((lba >> 32) & 0xFF) as u8;
-        fis[9] = ((lba >> 40) & 0xFF) as u8;
-        fis[10] = ((lba >> 48) & 0xFF) as u8;
-        fis[11] = 0x00;
-        fis[12] = (count & 0xFF) as u8;
-        fis[13] = ((count >> 8) & 0xFF) as u8;
-        
-        (*cmdtbl).prdt_entry[0].dba = buffer as u64;
-        (*cmdtbl).prdt_entry[0].dbc = (count as u32 * 512) - 1;
-        
-        let port_mut = port as *const HbaPort as *mut HbaPort;
-        (*port_mut).ci = 1;
-        
-        let mut timeout = 1000000;
-        while ((*port_mut).ci & 1) != 0 && timeout > 0 {
-            timeout -= 1;
-        }
+        fis[2] = ATA_CMD_IDENTIFY;
     }
-    
-    true
-}
\ No newline at end of file
+
+    ahci_issue_and_wait(port, slot).ok()?;
+
+    let buf = unsafe { &*dma_ptr::<[u8; 512]>(buffer_phys) };
+    let read_u32 = |offset: usize| u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]);
+
+    let sectors = if read_u32(ATA_IDENT_COMMANDSETS) & ATA_IDENT_LBA48 != 0 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[ATA_IDENT_MAX_LBA_EXT..ATA_IDENT_MAX_LBA_EXT + 8]);
+        u64::from_le_bytes(bytes)
+    } else {
+        read_u32(ATA_IDENT_MAX_LBA) as u64
+    };
+
+    // The model string is stored as big endian 16-bit words
+    let mut model = [0u8; 41];
+    for m in (0..40).step_by(2) {
+        model[m] = buf[ATA_IDENT_MODEL + m + 1];
+        model[m + 1] = buf[ATA_IDENT_MODEL + m];
+    }
+
+    Some(AhciIdentify { sectors, model })
+}
+
+/// The IDENTIFY data `probe_ports` stored for `port`, `None` if it has no SATA drive.
+pub fn ahci_port_identify(port: usize) -> Option<AhciIdentify> {
+    if port >= 32 {
+        return None;
+    }
+    unsafe { AHCI_IDENTIFY[port] }
+}
+
+/// Registers of `port` if `probe_ports` found a SATA drive on it.
+pub fn ahci_port(port: usize) -> Option<&'static mut HbaPort> {
+    let abar = ABAR_VIRT.load(Ordering::Relaxed);
+    if abar == 0 || ahci_port_identify(port).is_none() {
+        return None;
+    }
+    Some(unsafe { &mut (*(abar as *mut HbaMem)).ports[port] })
+}
+
+/// Recovers a hung port: stops the command engine, sends COMRESET through PxSCTL,
+/// waits for the drive to come back, clears PxSERR and restarts the engine.
+/// Returns `Timeout` if no drive reappears, the engine is left stopped then.
+pub fn ahci_port_reset(port: &mut HbaPort) -> Result<(), AhciError> {
+    stop_cmd(port);
+
+    let sctl = port.read_sctl() & !HBA_SCTL_DET_MASK;
+    port.write_sctl(sctl | HBA_SCTL_DET_COMRESET);
+    ahci_wait_until(AHCI_COMRESET_HOLD_MS, || false);
+    port.write_sctl(sctl);
+
+    if !ahci_wait_until(AHCI_TIMEOUT_MS, || (port.read_ssts() & HBA_SSTS_DET_MASK) == HBA_PORT_DET_PRESENT) {
+        println!("AHCI port reset: no device after COMRESET");
+        return Err(AhciError::Timeout);
+    }
+
+    // PxSERR and PxIS bits are cleared by writing them back
+    port.write_serr(port.read_serr());
+    port.write_is(port.read_is());
+
+    let busy = (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32;
+    if !ahci_wait_until(AHCI_TIMEOUT_MS, || (port.read_tfd() & busy) == 0) {
+        println!("AHCI port reset: device stayed busy");
+        return Err(AhciError::Timeout);
+    }
+
+    start_cmd(port);
+    Ok(())
+}
@@ -52,6 +52,10 @@ impl HbaPort {
         unsafe { read_volatile(&self.sig) }
     }
 
+    pub fn read_sact(&self) -> u32 {
+        unsafe { read_volatile(&self.sact) }
+    }
+
     pub fn read_ci(&self) -> u32 {
         unsafe { read_volatile(&self.ci) }
     }
@@ -60,6 +64,18 @@ impl HbaPort {
         unsafe { write_volatile(&mut self.ci, value) }
     }
 
+    pub fn read_sctl(&self) -> u32 {
+        unsafe { read_volatile(&self.sctl) }
+    }
+
+    pub fn write_sctl(&mut self, value: u32) {
+        unsafe { write_volatile(&mut self.sctl, value) }
+    }
+
+    pub fn read_serr(&self) -> u32 {
+        unsafe { read_volatile(&self.serr) }
+    }
+
     pub fn write_serr(&mut self, value: u32) {
         unsafe { write_volatile(&mut self.serr, value) }
     }
@@ -130,7 +146,7 @@ pub struct HbaCmdTbl {
     pub cfis: [u8; 64],
     pub acmd: [u8; 16],
     reserved: [u8; 48],
-    pub prdt_entry: [HbaPrdtEntry; 1],
+    pub prdt_entry: [HbaPrdtEntry; AHCI_MAX_PRDT_ENTRIES],
 }
 
 #[repr(C)]
@@ -236,6 +252,11 @@ pub const HBA_PORT_CMD_CR: u32 = 0x8000;
 
 pub const HBA_PX_IS_TFES: u32 = 1 << 30;
 
+/// Device detection field of PxSCTL, writing 1 sends COMRESET
+pub const HBA_SCTL_DET_MASK: u32 = 0x0F;
+pub const HBA_SCTL_DET_COMRESET: u32 = 0x1;
+pub const HBA_SSTS_DET_MASK: u32 = 0x0F;
+
 pub const HBA_PORT_SIG_ATA: u32 = 0x00000101;
 pub const HBA_PORT_SIG_ATAPI: u32 = 0xEB140101;
 pub const HBA_PORT_SIG_SEMB: u32 = 0xC33C0101;
@@ -246,11 +267,20 @@ pub const HBA_SSTS_IPM_ACTIVE: u32 = 0x1;
 
 pub const ATA_DEV_BUSY: u8 = 0x80;
 pub const ATA_DEV_DRQ: u8 = 0x08;
+pub const ATA_DEV_ERR: u8 = 0x01;
 
 pub const ATA_CMD_READ_DMA_EX: u8 = 0x25;
 pub const ATA_CMD_WRITE_DMA_EX: u8 = 0x35;
 pub const ATA_CMD_IDENTIFY: u8 = 0xEC;
 
+// Byte offsets into the 512-byte IDENTIFY DEVICE response
+pub const ATA_IDENT_MODEL: usize = 54;
+pub const ATA_IDENT_MAX_LBA: usize = 120;
+pub const ATA_IDENT_COMMANDSETS: usize = 164;
+pub const ATA_IDENT_MAX_LBA_EXT: usize = 200;
+/// Word 83 bit 10, the drive supports the 48-bit feature set
+pub const ATA_IDENT_LBA48: u32 = 1 << 26;
+
 pub const FIS_TYPE_REG_H2D: u8 = 0x27;
 pub const FIS_TYPE_REG_D2H: u8 = 0x34;
 pub const FIS_TYPE_DMA_ACT: u8 = 0x39;
@@ -264,6 +294,11 @@ pub const HBA_GHC_AE: u32 = 1 << 31;
 pub const HBA_GHC_IE: u32 = 1 << 1;
 pub const HBA_GHC_HR: u32 = 1 << 0;
 
+/// `rebase_port` gives every command table 256 bytes, room for 8 PRDT entries
+pub const AHCI_MAX_PRDT_ENTRIES: usize = 8;
+/// A single PRDT entry can describe at most 4 MiB
+pub const AHCI_PRDT_MAX_BYTES: u32 = 4 * 1024 * 1024;
+
 pub const AHCI_CMD_HEADER_FLAGS_FIS_LEN: u16 = 5;
 pub const AHCI_CMD_HEADER_FLAGS_WRITE: u16 = 1 << 6;
 pub const AHCI_CMD_HEADER_FLAGS_PREFETCHABLE: u16 = 1 << 7;
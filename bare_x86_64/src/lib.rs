@@ -96,4 +96,99 @@ macro_rules! outl {
             );
         }
     }};
-}
\ No newline at end of file
+}
+/// Waits roughly 1-4 microseconds by writing to the unused POST code port 0x80.
+/// Old PICs and PITs need this between consecutive command writes.
+/// Clobbers nothing: `al` is an input and the flags are preserved.
+#[macro_export]
+macro_rules! io_wait {
+    () => {{
+        unsafe {
+            core::arch::asm!(
+                "out 0x80, al",
+                in("al") 0u8,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+/// Reads `$count` words from `$port` into `$buffer` (`*mut u16`) with `rep insw`.
+/// Clobbers `rdi` and `rcx`, writes `$count * 2` bytes of memory, relies on DF being clear.
+#[macro_export]
+macro_rules! insw {
+    ($port:expr, $buffer:expr, $count:expr) => {{
+        let port: u16 = $port;
+        let buffer: *mut u16 = $buffer;
+        let count: usize = $count;
+        unsafe {
+            core::arch::asm!(
+                "rep insw",
+                in("dx") port,
+                inout("rdi") buffer => _,
+                inout("rcx") count => _,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+/// Writes `$count` words from `$buffer` (`*const u16`) to `$port` with `rep outsw`.
+/// Clobbers `rsi` and `rcx`, only reads memory, relies on DF being clear.
+#[macro_export]
+macro_rules! outsw {
+    ($port:expr, $buffer:expr, $count:expr) => {{
+        let port: u16 = $port;
+        let buffer: *const u16 = $buffer;
+        let count: usize = $count;
+        unsafe {
+            core::arch::asm!(
+                "rep outsw",
+                in("dx") port,
+                inout("rsi") buffer => _,
+                inout("rcx") count => _,
+                options(readonly, nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+/// Reads `$count` dwords from `$port` into `$buffer` (`*mut u32`) with `rep insd`.
+/// Clobbers `rdi` and `rcx`, writes `$count * 4` bytes of memory, relies on DF being clear.
+#[macro_export]
+macro_rules! insl {
+    ($port:expr, $buffer:expr, $count:expr) => {{
+        let port: u16 = $port;
+        let buffer: *mut u32 = $buffer;
+        let count: usize = $count;
+        unsafe {
+            core::arch::asm!(
+                "rep insd",
+                in("dx") port,
+                inout("rdi") buffer => _,
+                inout("rcx") count => _,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+/// Writes `$count` dwords from `$buffer` (`*const u32`) to `$port` with `rep outsd`.
+/// Clobbers `rsi` and `rcx`, only reads memory, relies on DF being clear.
+#[macro_export]
+macro_rules! outsl {
+    ($port:expr, $buffer:expr, $count:expr) => {{
+        let port: u16 = $port;
+        let buffer: *const u32 = $buffer;
+        let count: usize = $count;
+        unsafe {
+            core::arch::asm!(
+                "rep outsd",
+                in("dx") port,
+                inout("rsi") buffer => _,
+                inout("rcx") count => _,
+                options(readonly, nostack, preserves_flags)
+            );
+        }
+    }};
+}
@@ -1,7 +1,7 @@
 #![no_std]
 
 use core::fmt;
-use core::cell::UnsafeCell;
+use core::cell::{Cell, UnsafeCell};
 
 #[repr(C, packed)]
 struct PSF1Header {
@@ -41,27 +41,86 @@ impl RendererCell {
         }
     }
 
-    fn get(&self) -> &mut ScrollingTextRenderer {
-        unsafe {
-            (*self.inner.get())
-                .as_mut()
-                .expect("Renderer not initialized")
-        }
+    /// Raw pointer to the renderer slot, callers must not hold two references from it at once.
+    fn as_ptr(&self) -> *mut Option<ScrollingTextRenderer> {
+        self.inner.get()
     }
 }
 
 static RENDERER: RendererCell = RendererCell::new();
 
+/// Colors picked by SGR 30-37/40-47, bold turns the foreground into the bright row
+const ANSI_COLORS: [u32; 8] = [0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA];
+const ANSI_BRIGHT_COLORS: [u32; 8] = [0x555555, 0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF];
+/// Parameters past this many in one escape sequence are dropped
+const ANSI_MAX_PARAMS: usize = 8;
+
+/// Where `write_char` is inside an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    /// Just saw ESC
+    Escape,
+    /// Inside `ESC [`, collecting parameters until the final byte
+    Csi,
+}
+
+/// What `write_char` does with text that runs past the right edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineMode {
+    /// Continue on the next line
+    Wrap,
+    /// Drop everything up to the next newline
+    Truncate,
+}
+
+/// Where each color channel sits in a framebuffer pixel, as `(shift, size)` in bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelLayout {
+    pub red: (u8, u8),
+    pub green: (u8, u8),
+    pub blue: (u8, u8),
+}
+
+impl ChannelLayout {
+    /// The usual layout for a depth: XRGB8888 at 32 bpp, RGB888 at 24, RGB565 at 16
+    pub const fn for_bpp(bpp: usize) -> Self {
+        if bpp == 16 {
+            Self { red: (11, 5), green: (5, 6), blue: (0, 5) }
+        } else {
+            Self { red: (16, 8), green: (8, 8), blue: (0, 8) }
+        }
+    }
+}
+
 pub struct ScrollingTextRenderer {
+    /// Where drawing goes, either VRAM or the backbuffer
     framebuffer: *mut u8,
+    vram: *mut u8,
+    backbuffer_len: usize,
+    /// Rows touched since the last `present`, empty when start >= end
+    dirty_rows: Cell<(usize, usize)>,
     width: usize,
     height: usize,
     pitch: usize,
     bpp: usize,
+    channels: ChannelLayout,
     x: usize,
     y: usize,
     fg_color: u32,
     bg_color: u32,
+    /// Tab stops every this many columns
+    tab_width: usize,
+    line_mode: LineMode,
+    /// Colors from `set_colors`, SGR 0 goes back to these
+    default_fg: u32,
+    default_bg: u32,
+    ansi_state: AnsiState,
+    ansi_params: [u16; ANSI_MAX_PARAMS],
+    ansi_param_count: usize,
+    /// Foreground palette index set by SGR, kept so bold can switch it to the bright color
+    ansi_fg: Option<usize>,
+    ansi_bold: bool,
     font_data: &'static [u8],
     char_width: usize,
     char_height: usize,
@@ -84,14 +143,27 @@ impl ScrollingTextRenderer {
         
         let renderer = Self {
             framebuffer,
+            vram: framebuffer,
+            backbuffer_len: 0,
+            dirty_rows: Cell::new((0, 0)),
             width,
             height,
             pitch,
             bpp,
+            channels: ChannelLayout::for_bpp(bpp),
             x: 0,
             y: 0,
             fg_color: 0xFFFFFF,
             bg_color: 0x000000,
+            tab_width: 4,
+            line_mode: LineMode::Wrap,
+            default_fg: 0xFFFFFF,
+            default_bg: 0x000000,
+            ansi_state: AnsiState::Ground,
+            ansi_params: [0; ANSI_MAX_PARAMS],
+            ansi_param_count: 0,
+            ansi_fg: None,
+            ansi_bold: false,
             font_data,
             char_width,
             char_height,
@@ -102,36 +174,122 @@ impl ScrollingTextRenderer {
     }
 
     pub fn get() -> &'static mut Self {
-        RENDERER.get()
+        unsafe {
+            (*RENDERER.as_ptr())
+                .as_mut()
+                .expect("Renderer not initialized")
+        }
+    }
+
+    /// Like `get` but returns `None` instead of panicking before `init`, for use on the panic path.
+    pub fn try_get() -> Option<&'static mut Self> {
+        unsafe { (*RENDERER.as_ptr()).as_mut() }
     }
 
     fn parse_psf(data: &[u8]) -> (usize, usize, usize) {
-        if data.len() >= 32 && &data[0..4] == b"\x72\xb5\x4a\x86" {
-            let header = unsafe { &*(data.as_ptr() as *const PSF2Header) };
-            return (
-                header.width as usize,
-                header.height as usize,
-                header.bytesperglyph as usize,
-            );
+        Self::try_parse_psf(data).unwrap_or((8, 16, 16))
+    }
+
+    /// Returns `(width, height, bytes_per_glyph)` if `data` is a usable PSF1 or PSF2 font.
+    fn try_parse_psf(data: &[u8]) -> Option<(usize, usize, usize)> {
+        let (width, height, bytes_per_glyph, header_size, glyph_count) =
+            if data.len() >= 32 && &data[0..4] == b"\x72\xb5\x4a\x86" {
+                let header = unsafe { &*(data.as_ptr() as *const PSF2Header) };
+                (
+                    header.width as usize,
+                    header.height as usize,
+                    header.bytesperglyph as usize,
+                    header.headersize as usize,
+                    header.numglyph as usize,
+                )
+            } else if data.len() >= 4 && &data[0..2] == b"\x36\x04" {
+                let header = unsafe { &*(data.as_ptr() as *const PSF1Header) };
+                let height = header.charsize as usize;
+                // Mode bit 0 means the font has 512 glyphs instead of 256
+                let glyph_count = if header.mode & 0x01 != 0 { 512 } else { 256 };
+                (8, height, height, 4, glyph_count)
+            } else {
+                return None;
+            };
+
+        // Every glyph row has to fit in the glyph and the font needs at least one glyph
+        if width == 0 || height == 0 || bytes_per_glyph < height * width.div_ceil(8) {
+            return None;
         }
-        
-        if data.len() >= 4 && &data[0..2] == b"\x36\x04" {
-            let header = unsafe { &*(data.as_ptr() as *const PSF1Header) };
-            let height = header.charsize as usize;
-            let width = 8;
-            let bytes_per_glyph = height;
-            return (width, height, bytes_per_glyph);
+        // Every glyph the header advertises has to be inside the file
+        let glyphs_end = glyph_count
+            .max(1)
+            .checked_mul(bytes_per_glyph)?
+            .checked_add(header_size)?;
+        if glyphs_end > data.len() {
+            return None;
         }
-        
-        (8, 16, 16)
+
+        Some((width, height, bytes_per_glyph))
+    }
+
+    /// Switches the renderer to a new framebuffer size and clears it.
+    /// The cursor goes back to the top left corner.
+    pub fn resize(&mut self, width: usize, height: usize, pitch: usize) {
+        self.width = width;
+        self.height = height;
+        self.pitch = pitch;
+
+        // Fall back to drawing straight to VRAM if the backbuffer can't hold the new size
+        if self.backbuffer_len != 0 && self.backbuffer_len < pitch * height {
+            self.framebuffer = self.vram;
+            self.backbuffer_len = 0;
+        }
+
+        self.clear();
+    }
+
+    /// The PSF font currently in use.
+    pub fn font(&self) -> &'static [u8] {
+        self.font_data
+    }
+
+    /// Width and height of one character cell in pixels.
+    pub fn char_size(&self) -> (usize, usize) {
+        (self.char_width, self.char_height)
     }
 
-    fn get_glyph_offset(&self, ch: char) -> usize {
+    /// Switches to another PSF font, the current font is kept if `font_data` can't be parsed.
+    pub fn set_font(&mut self, font_data: &'static [u8]) -> Result<(), &'static str> {
+        let (char_width, char_height, bytes_per_glyph) =
+            Self::try_parse_psf(font_data).ok_or("Invalid PSF font")?;
+
+        self.font_data = font_data;
+        self.char_width = char_width;
+        self.char_height = char_height;
+        self.bytes_per_glyph = bytes_per_glyph;
+
+        // Keep the cursor on the new character grid
+        self.x = (self.x / char_width) * char_width;
+        self.y = (self.y / char_height) * char_height;
+        if self.y + char_height > self.height {
+            self.y = self.height.saturating_sub(char_height) / char_height * char_height;
+        }
+
+        Ok(())
+    }
+
+    /// Offset of the glyph for `ch`, glyph 0 if the font doesn't have it.
+    /// `None` if not even glyph 0 fits in the font data.
+    fn get_glyph_offset(&self, ch: char) -> Option<usize> {
+        let header_size = self.header_size();
+        if self.bytes_per_glyph == 0 || header_size > self.font_data.len() {
+            return None;
+        }
+
         let idx = ch as usize;
-        let max_glyphs = (self.font_data.len() - self.header_size()) / self.bytes_per_glyph;
-        
+        let max_glyphs = (self.font_data.len() - header_size) / self.bytes_per_glyph;
+        if max_glyphs == 0 {
+            return None;
+        }
+
         let glyph_idx = if idx < max_glyphs { idx } else { 0 };
-        self.header_size() + glyph_idx * self.bytes_per_glyph
+        Some(header_size + glyph_idx * self.bytes_per_glyph)
     }
 
     fn header_size(&self) -> usize {
@@ -143,28 +301,268 @@ impl ScrollingTextRenderer {
         }
     }
 
+    /// Moves the text cursor to a character cell, clamped to the last column and row on screen.
+    pub fn set_cursor(&mut self, col: usize, row: usize) {
+        let cols = (self.width / self.char_width).max(1);
+        let rows = (self.height / self.char_height).max(1);
+
+        self.x = col.min(cols - 1) * self.char_width;
+        self.y = row.min(rows - 1) * self.char_height;
+    }
+
+    /// The text cursor as `(col, row)` in character cells.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.x / self.char_width, self.y / self.char_height)
+    }
+
+    /// Sets the text colors, these are also what an SGR reset (`ESC[0m`) returns to.
     pub fn set_colors(&mut self, fg: u32, bg: u32) {
         self.fg_color = fg;
         self.bg_color = bg;
+        self.default_fg = fg;
+        self.default_bg = bg;
+        self.ansi_fg = None;
+        self.ansi_bold = false;
+    }
+
+    /// Sets the distance between tab stops in columns, at least 1.
+    pub fn set_tab_width(&mut self, cols: usize) {
+        self.tab_width = cols.max(1);
+    }
+
+    pub fn set_line_mode(&mut self, mode: LineMode) {
+        self.line_mode = mode;
     }
 
-    fn put_pixel(&self, x: usize, y: usize, color: u32) {
+    /// Sends all drawing to `buffer` in RAM, VRAM is only touched by `present`.
+    /// Text output presents on its own, after `put_pixel` call `present` yourself.
+    /// `buffer` has to hold at least `pitch * height` bytes and be 4-byte aligned.
+    pub fn enable_backbuffer(&mut self, buffer: &'static mut [u8]) -> Result<(), &'static str> {
+        let size = self.pitch * self.height;
+        if buffer.len() < size {
+            return Err("Backbuffer is smaller than the framebuffer");
+        }
+        // put_pixel stores whole u32 pixels at 32 bpp
+        if !buffer.as_ptr().cast::<u32>().is_aligned() {
+            return Err("Backbuffer is not 4-byte aligned");
+        }
+
+        // Start from what is on screen now
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.vram, buffer.as_mut_ptr(), size);
+        }
+
+        self.framebuffer = buffer.as_mut_ptr();
+        self.backbuffer_len = buffer.len();
+        self.dirty_rows.set((0, 0));
+        Ok(())
+    }
+
+    /// Copies the rows drawn since the last call from the backbuffer to VRAM.
+    /// Does nothing without a backbuffer.
+    pub fn present(&mut self) {
+        let (start, end) = self.dirty_rows.get();
+        self.dirty_rows.set((0, 0));
+        if self.backbuffer_len == 0 || start >= end {
+            return;
+        }
+
+        let end = end.min(self.height);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.framebuffer.add(start * self.pitch),
+                self.vram.add(start * self.pitch),
+                (end - start) * self.pitch,
+            );
+        }
+    }
+
+    fn mark_dirty(&self, start: usize, end: usize) {
+        if self.backbuffer_len == 0 {
+            return;
+        }
+
+        let (dirty_start, dirty_end) = self.dirty_rows.get();
+        if dirty_start >= dirty_end {
+            self.dirty_rows.set((start, end));
+        } else {
+            self.dirty_rows.set((dirty_start.min(start), dirty_end.max(end)));
+        }
+    }
+
+    /// Screen size in pixels as `(width, height)`.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Sets a single 0xRRGGBB pixel, anything off screen is ignored.
+    pub fn put_pixel(&self, x: usize, y: usize, color: u32) {
+        self.put_packed(x, y, self.pack_color(color));
+    }
+
+    /// Uses `layout` instead of the default one for the framebuffer depth,
+    /// for framebuffers that store the channels in another order.
+    pub fn set_channel_layout(&mut self, layout: ChannelLayout) {
+        self.channels = layout;
+    }
+
+    /// Converts a 0xRRGGBB color into the framebuffer's pixel format.
+    pub fn pack_color(&self, color: u32) -> u32 {
+        let channel = |value: u32, (shift, size): (u8, u8)| {
+            let value = value & 0xFF;
+            let scaled = if size >= 8 { value << (size - 8) } else { value >> (8 - size) };
+            scaled << shift
+        };
+        channel(color >> 16, self.channels.red)
+            | channel(color >> 8, self.channels.green)
+            | channel(color, self.channels.blue)
+    }
+
+    /// Writes a pixel already converted by `pack_color`.
+    fn put_packed(&self, x: usize, y: usize, packed: u32) {
         if x >= self.width || y >= self.height {
             return;
         }
 
+        self.mark_dirty(y, y + 1);
+
         let offset = y * self.pitch + x * (self.bpp / 8);
         unsafe {
-            let pixel = self.framebuffer.add(offset) as *mut u32;
-            *pixel = color;
+            let pixel = self.framebuffer.add(offset);
+            match self.bpp {
+                32 => (pixel as *mut u32).write_volatile(packed),
+                24 => {
+                    pixel.write_volatile(packed as u8);
+                    pixel.add(1).write_volatile((packed >> 8) as u8);
+                    pixel.add(2).write_volatile((packed >> 16) as u8);
+                }
+                16 => (pixel as *mut u16).write_volatile(packed as u16),
+                _ => {}
+            }
         }
     }
 
+    /// Fills a `w` x `h` rectangle with its top left corner at `(x, y)`, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32) {
+        let x_end = x.saturating_add(w).min(self.width);
+        let y_end = y.saturating_add(h).min(self.height);
+
+        for py in y..y_end {
+            for px in x..x_end {
+                self.put_pixel(px, py, color);
+            }
+        }
+
+        self.present();
+    }
+
+    /// Copies a `width` x `height` buffer of 0xRRGGBB pixels to `(x, y)`, each one
+    /// converted through `pack_color`.
+    /// Anything past the screen edge or past the end of `pixels` is clipped.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[u32]) {
+        'rows: for row in 0..height {
+            let screen_y = match y.checked_add(row) {
+                Some(screen_y) if screen_y < self.height => screen_y,
+                _ => break,
+            };
+
+            for col in 0..width {
+                let screen_x = match x.checked_add(col) {
+                    Some(screen_x) if screen_x < self.width => screen_x,
+                    _ => break,
+                };
+
+                let index = row.checked_mul(width).and_then(|start| start.checked_add(col));
+                match index.and_then(|index| pixels.get(index)) {
+                    Some(&color) => self.put_packed(screen_x, screen_y, self.pack_color(color)),
+                    None => break 'rows,
+                }
+            }
+        }
+
+        self.present();
+    }
+
+    /// Draws an uncompressed 24 or 32-bit BMP image with its top left corner at `(x, y)`.
+    pub fn blit_bmp(&mut self, x: usize, y: usize, data: &[u8]) -> Result<(), &'static str> {
+        if data.len() < 54 || &data[0..2] != b"BM" {
+            return Err("Not a BMP image");
+        }
+
+        let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+        };
+
+        let pixel_offset = read_u32(10) as usize;
+        let width = read_u32(18) as i32;
+        let height = read_u32(22) as i32;
+        let bpp = read_u16(28) as usize;
+        let compression = read_u32(30);
+
+        // 32-bit images are often stored as BI_BITFIELDS with the default BGRA masks
+        if compression != 0 && !(compression == 3 && bpp == 32) {
+            return Err("Compressed BMP images are not supported");
+        }
+        if bpp != 24 && bpp != 32 {
+            return Err("Only 24 and 32-bit BMP images are supported");
+        }
+        if width <= 0 || height == 0 {
+            return Err("Invalid BMP dimensions");
+        }
+
+        let width = width as usize;
+        let top_down = height < 0;
+        let height = height.unsigned_abs() as usize;
+        let bytes_per_pixel = bpp / 8;
+        let row_size = width
+            .checked_mul(bytes_per_pixel)
+            .and_then(|bytes| bytes.checked_add(3))
+            .ok_or("BMP dimensions overflow")?
+            & !3;
+        let end = row_size
+            .checked_mul(height)
+            .and_then(|size| size.checked_add(pixel_offset))
+            .ok_or("BMP dimensions overflow")?;
+
+        if end > data.len() {
+            return Err("BMP pixel data is truncated");
+        }
+
+        for row in 0..height {
+            let screen_y = match y.checked_add(row) {
+                Some(screen_y) if screen_y < self.height => screen_y,
+                _ => break,
+            };
+
+            // Rows are stored bottom-up unless the height is negative
+            let src_row = if top_down { row } else { height - 1 - row };
+            let row_start = pixel_offset + src_row * row_size;
+
+            for col in 0..width {
+                let screen_x = match x.checked_add(col) {
+                    Some(screen_x) if screen_x < self.width => screen_x,
+                    _ => break,
+                };
+
+                let p = row_start + col * bytes_per_pixel;
+                let color = ((data[p + 2] as u32) << 16) | ((data[p + 1] as u32) << 8) | data[p] as u32;
+                self.put_packed(screen_x, screen_y, self.pack_color(color));
+            }
+        }
+
+        self.present();
+        Ok(())
+    }
+
     fn draw_char(&self, ch: char, x: usize, y: usize) {
-        let glyph_offset = self.get_glyph_offset(ch);
-        let glyph_data = &self.font_data[glyph_offset..glyph_offset + self.bytes_per_glyph];
+        // Draw a blank cell rather than panic on a broken font
+        let glyph_data = match self.get_glyph_offset(ch) {
+            Some(offset) => &self.font_data[offset..offset + self.bytes_per_glyph],
+            None => &[],
+        };
         
-        let bytes_per_line = (self.char_width + 7) / 8;
+        let bytes_per_line = self.char_width.div_ceil(8);
         
         for row in 0..self.char_height {
             let line_offset = row * bytes_per_line;
@@ -173,11 +571,9 @@ impl ScrollingTextRenderer {
                 let byte_idx = line_offset + (col / 8);
                 let bit_idx = 7 - (col % 8);
                 
-                if byte_idx < glyph_data.len() {
-                    let bit = (glyph_data[byte_idx] >> bit_idx) & 1;
-                    let color = if bit == 1 { self.fg_color } else { self.bg_color };
-                    self.put_pixel(x + col, y + row, color);
-                }
+                let bit = glyph_data.get(byte_idx).map_or(0, |byte| (byte >> bit_idx) & 1);
+                let color = if bit == 1 { self.fg_color } else { self.bg_color };
+                self.put_pixel(x + col, y + row, color);
             }
         }
     }
@@ -186,17 +582,15 @@ impl ScrollingTextRenderer {
         let line_height = self.char_height;
         let bytes_per_pixel = self.bpp / 8;
         
+        // Copy whole rows as bytes so the pixel format doesn't matter
+        let row_bytes = self.width * bytes_per_pixel;
         unsafe {
             for y in line_height..self.height {
-                for x in 0..self.width {
-                    let src_offset = y * self.pitch + x * bytes_per_pixel;
-                    let dst_offset = (y - line_height) * self.pitch + x * bytes_per_pixel;
-                    
-                    let src = self.framebuffer.add(src_offset) as *const u32;
-                    let dst = self.framebuffer.add(dst_offset) as *mut u32;
-                    *dst = *src;
-                }
+                let src = self.framebuffer.add(y * self.pitch);
+                let dst = self.framebuffer.add((y - line_height) * self.pitch);
+                core::ptr::copy(src, dst, row_bytes);
             }
+            self.mark_dirty(0, self.height);
             
             let start_y = self.height - line_height;
             for y in start_y..self.height {
@@ -209,7 +603,91 @@ impl ScrollingTextRenderer {
         self.y -= line_height;
     }
 
+    /// Feeds one character of an escape sequence to the parser.
+    /// Returns true if it was consumed and must not be drawn.
+    fn ansi_char(&mut self, ch: char) -> bool {
+        match self.ansi_state {
+            AnsiState::Ground => {
+                if ch == '\x1b' {
+                    self.ansi_state = AnsiState::Escape;
+                    return true;
+                }
+                false
+            }
+            AnsiState::Escape => {
+                if ch == '[' {
+                    self.ansi_state = AnsiState::Csi;
+                    self.ansi_params = [0; ANSI_MAX_PARAMS];
+                    self.ansi_param_count = 0;
+                    return true;
+                }
+                // Only CSI sequences are understood, anything else is printed as is
+                self.ansi_state = AnsiState::Ground;
+                false
+            }
+            AnsiState::Csi => {
+                match ch {
+                    '0'..='9' => {
+                        if self.ansi_param_count == 0 {
+                            self.ansi_param_count = 1;
+                        }
+                        if let Some(param) = self.ansi_params.get_mut(self.ansi_param_count - 1) {
+                            *param = param.saturating_mul(10).saturating_add(ch as u16 - '0' as u16);
+                        }
+                    }
+                    ';' => self.ansi_param_count = (self.ansi_param_count.max(1) + 1).min(ANSI_MAX_PARAMS + 1),
+                    // Final byte, only SGR does anything
+                    '\x40'..='\x7e' => {
+                        if ch == 'm' {
+                            self.apply_sgr();
+                        }
+                        self.ansi_state = AnsiState::Ground;
+                    }
+                    // Intermediate and private marker bytes
+                    '\x20'..='\x3f' => {}
+                    _ => self.ansi_state = AnsiState::Ground,
+                }
+                true
+            }
+        }
+    }
+
+    /// Applies the parameters of a finished `ESC[...m` sequence, unknown ones are ignored.
+    fn apply_sgr(&mut self) {
+        let count = self.ansi_param_count.clamp(1, ANSI_MAX_PARAMS);
+        for i in 0..count {
+            match self.ansi_params[i] {
+                0 => {
+                    self.fg_color = self.default_fg;
+                    self.bg_color = self.default_bg;
+                    self.ansi_fg = None;
+                    self.ansi_bold = false;
+                }
+                1 => self.ansi_bold = true,
+                22 => self.ansi_bold = false,
+                code @ 30..=37 => self.ansi_fg = Some((code - 30) as usize),
+                39 => {
+                    self.ansi_fg = None;
+                    self.fg_color = self.default_fg;
+                }
+                code @ 40..=47 => self.bg_color = ANSI_COLORS[(code - 40) as usize],
+                49 => self.bg_color = self.default_bg,
+                _ => {}
+            }
+        }
+
+        if let Some(index) = self.ansi_fg {
+            self.fg_color = if self.ansi_bold { ANSI_BRIGHT_COLORS[index] } else { ANSI_COLORS[index] };
+        }
+    }
+
+    /// Draws `ch` at the cursor. `ESC[...m` color sequences (30-37, 40-47, 0, 1) change the
+    /// colors instead of being drawn, other escape sequences are swallowed.
     pub fn write_char(&mut self, ch: char) {
+        if self.ansi_char(ch) {
+            return;
+        }
+
         match ch {
             '\n' => {
                 self.x = 0;
@@ -219,15 +697,23 @@ impl ScrollingTextRenderer {
                 self.x = 0;
             }
             '\t' => {
-                let tab_width = self.char_width * 4;
+                let tab_width = self.char_width * self.tab_width;
                 self.x = ((self.x + tab_width) / tab_width) * tab_width;
                 if self.x >= self.width {
-                    self.x = 0;
-                    self.y += self.char_height;
+                    match self.line_mode {
+                        LineMode::Wrap => {
+                            self.x = 0;
+                            self.y += self.char_height;
+                        }
+                        LineMode::Truncate => self.x = self.width,
+                    }
                 }
             }
             _ => {
                 if self.x + self.char_width > self.width {
+                    if self.line_mode == LineMode::Truncate {
+                        return;
+                    }
                     self.x = 0;
                     self.y += self.char_height;
                 }
@@ -246,6 +732,7 @@ impl ScrollingTextRenderer {
         for ch in s.chars() {
             self.write_char(ch);
         }
+        self.present();
     }
 
     pub fn clear(&mut self) {
@@ -256,6 +743,7 @@ impl ScrollingTextRenderer {
         }
         self.x = 0;
         self.y = 0;
+        self.present();
     }
 
     pub fn panic_print(&mut self, s: &str) {
@@ -291,6 +779,8 @@ impl ScrollingTextRenderer {
             self.x = 0;
             self.y += self.char_height;
         }
+
+        self.present();
     }
 
     pub fn panic_write_str(&mut self, s: &str) {
@@ -363,6 +853,9 @@ macro_rules! panic_print {
         
         let mut buffer = StackString::new();
         let _ = write!(&mut buffer, $($arg)*);
-        $crate::ScrollingTextRenderer::get().panic_write_str(buffer.as_str());
+        // Panicking again here would recurse, so skip the screen if there is no renderer yet
+        if let Some(renderer) = $crate::ScrollingTextRenderer::try_get() {
+            renderer.panic_write_str(buffer.as_str());
+        }
     }};
 }
\ No newline at end of file
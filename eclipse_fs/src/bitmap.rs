@@ -1,5 +1,5 @@
 use alloc::{vec::Vec, vec};
-use ide::{ide_read_sectors, ide_write_sectors};
+use ide::ide_get_drive;
 use super::SuperBlock;
 
 #[derive(Debug)]
@@ -8,6 +8,8 @@ pub enum BitmapError {
     FailedToWriteBitmap,
     InvalidDrive,
     InvalidBlock,
+    /// The loaded bitmap does not match the superblock layout
+    Inconsistent,
 }
 
 pub struct BlockBitmap {
@@ -32,9 +34,7 @@ impl BlockBitmap {
     }
 
     pub fn from_disk(drive: usize, super_block: &SuperBlock) -> Result<Self, BitmapError> {
-        if drive >= 4 {
-            return Err(BitmapError::InvalidDrive);
-        }
+        let disk = ide_get_drive(drive).ok_or(BitmapError::InvalidDrive)?;
 
         let total_blocks = super_block.blocks();
         let bitmap_bytes = ((total_blocks + 7) / 8) as usize;
@@ -48,7 +48,7 @@ impl BlockBitmap {
             let sector = start_sector + sector_offset;
             let offset = (sector_offset as usize) * 512;
             
-            if ide_read_sectors(drive, sector, &mut bits[offset..offset + 512]) != 0 {
+            if disk.read(sector, &mut bits[offset..offset + 512]).is_err() {
                 return Err(BitmapError::FailedToReadBitmap);
             }
         }
@@ -62,24 +62,16 @@ impl BlockBitmap {
     }
 
     pub fn write_to_disk(&self, drive: usize, super_block: &SuperBlock) -> Result<(), BitmapError> {
-        if drive >= 4 {
-            return Err(BitmapError::InvalidDrive);
-        }
+        let disk = ide_get_drive(drive).ok_or(BitmapError::InvalidDrive)?;
 
         let sectors_per_block = (super_block.block_size / 512) as u64;
         let start_sector = super_block.block_bitmap_start * sectors_per_block;
         
-        let mut padded_bits = self.bits.clone();
-        let remainder = padded_bits.len() % 512;
-        if remainder != 0 {
-            padded_bits.resize(padded_bits.len() + (512 - remainder), 0);
-        }
-
-        for (i, chunk) in padded_bits.chunks(512).enumerate() {
+        for (i, chunk) in self.to_sectors().chunks(512).enumerate() {
             let sector = start_sector + i as u64;
-            let mut buffer = chunk.to_vec();
+            let buffer = chunk.to_vec();
             
-            if ide_write_sectors(drive, sector, &mut buffer) != 0 {
+            if disk.write(sector, &buffer).is_err() {
                 return Err(BitmapError::FailedToWriteBitmap);
             }
         }
@@ -87,6 +79,16 @@ impl BlockBitmap {
         Ok(())
     }
 
+    /// The bitmap bytes padded with zeros to a whole number of sectors.
+    pub fn to_sectors(&self) -> Vec<u8> {
+        let mut padded_bits = self.bits.clone();
+        let remainder = padded_bits.len() % 512;
+        if remainder != 0 {
+            padded_bits.resize(padded_bits.len() + (512 - remainder), 0);
+        }
+        padded_bits
+    }
+
     pub fn allocate_block(&mut self) -> Result<u64, BitmapError> {
         for block in 0..self.total_blocks as usize {
             if !self.is_allocated(block) {
@@ -154,4 +156,36 @@ impl BlockBitmap {
     pub fn used_blocks(&self) -> u64 {
         self.total_blocks - self.free_blocks()
     }
-}
\ No newline at end of file
+
+    /// Checks the bitmap against the superblock: the block count must match, no bit may be
+    /// set past the last block and every block before the data region (superblock, inode
+    /// table, bitmap, reserved) must be used.
+    pub fn verify(&self, super_block: &SuperBlock) -> Result<(), BitmapError> {
+        if self.total_blocks != super_block.blocks()
+            || self.bits.len() * 8 < self.total_blocks as usize
+        {
+            return Err(BitmapError::Inconsistent);
+        }
+
+        // Bits past the last block are padding and must stay clear
+        for block in self.total_blocks as usize..self.bits.len() * 8 {
+            if self.bits[block / 8] & (1 << (block % 8)) != 0 {
+                return Err(BitmapError::Inconsistent);
+            }
+        }
+
+        // Counted straight from the bytes, so this catches a free count that drifted
+        let set_bits: u64 = self.bits.iter().map(|byte| byte.count_ones() as u64).sum();
+        if set_bits != self.used_blocks() {
+            return Err(BitmapError::Inconsistent);
+        }
+
+        for block in 0..super_block.data_region_start.min(self.total_blocks) {
+            if !self.is_allocated(block as usize) {
+                return Err(BitmapError::Inconsistent);
+            }
+        }
+
+        Ok(())
+    }
+}
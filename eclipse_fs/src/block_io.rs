@@ -1,9 +1,11 @@
 use alloc::vec;
 use eclipse_framebuffer::println;
-use ide::{ide_read_sectors, ide_write_sectors};
-use ahci::{HbaPort, ahci_read, ahci_write};
+use ide::{ide_get_drive, IdeError};
+use ahci::{AhciError, HbaPort};
+use crate::{AhciDriver, IdeDriver, StorageDriver};
 use crate::super_block::SuperBlock;
 use crate::bitmap::{BlockBitmap, BitmapError};
+use crate::retry::{IO_RETRY_ATTEMPTS, block_failure, retry_io};
 
 #[derive(Debug)]
 pub enum BlockError {
@@ -14,6 +16,15 @@ pub enum BlockError {
     InvalidDrive,
     BlockNotAllocated,
     BitmapError(BitmapError),
+    /// The drive itself reported the failure
+    DeviceError(DeviceError),
+}
+
+/// Error reported by the driver behind a block access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    Ide(IdeError),
+    Ahci(AhciError),
 }
 
 impl From<BitmapError> for BlockError {
@@ -22,6 +33,18 @@ impl From<BitmapError> for BlockError {
     }
 }
 
+impl From<IdeError> for BlockError {
+    fn from(err: IdeError) -> Self {
+        BlockError::DeviceError(DeviceError::Ide(err))
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(err: AhciError) -> Self {
+        BlockError::DeviceError(DeviceError::Ahci(err))
+    }
+}
+
 pub fn read_block(
     drive: usize,
     super_block: &SuperBlock,
@@ -31,9 +54,7 @@ pub fn read_block(
     let block_count = super_block.blocks;
     let mut block_size = super_block.block_size;
     
-    if drive >= 4 {
-        return Err(BlockError::InvalidDrive);
-    }
+    let driver = IdeDriver { drive: ide_get_drive(drive).ok_or(BlockError::InvalidDrive)? };
     
     if block >= block_count {
         println!("Block {} is greater than or equal to Block Count: {}", block, block_count);
@@ -55,9 +76,7 @@ pub fn read_block(
     
     println!("Read lba: {}", lba);
     
-    if ide_read_sectors(drive, lba, &mut buffer) != 0 {
-        return Err(BlockError::ReadFailed);
-    }
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.read_sector(lba, &mut buffer))?;
     
     buffer.truncate(super_block.block_size as usize);
     Ok(buffer)
@@ -73,9 +92,7 @@ pub fn write_block(
     let block_count = super_block.blocks;
     let mut block_size = super_block.block_size;
     
-    if drive >= 4 {
-        return Err(BlockError::InvalidDrive);
-    }
+    let driver = IdeDriver { drive: ide_get_drive(drive).ok_or(BlockError::InvalidDrive)? };
     
     if block >= block_count {
         println!("Block {} is greater than or equal to Block Count: {}", block, block_count);
@@ -115,13 +132,43 @@ pub fn write_block(
     
     println!("Write lba: {}", lba);
     
-    if ide_write_sectors(drive, lba, &buffer) != 0 {
-        return Err(BlockError::WriteFailed);
-    }
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.write_sector(lba, &buffer))?;
     
     Ok(())
 }
 
+/// Reads `len` bytes starting `offset` bytes past the start of sector `lba` into `out`.
+/// Only the sectors covering that window are read, one at a time through a 512 byte scratch buffer.
+pub fn read_partial(
+    drive: usize,
+    lba: u64,
+    offset: usize,
+    len: usize,
+    out: &mut [u8],
+) -> Result<(), BlockError> {
+    if out.len() < len {
+        println!("Output buffer of {} bytes is too small for {} bytes", out.len(), len);
+        return Err(BlockError::InvalidBlockSize);
+    }
+
+    let driver = IdeDriver { drive: ide_get_drive(drive).ok_or(BlockError::InvalidDrive)? };
+    let mut sector = [0u8; 512];
+    let mut copied = 0;
+
+    while copied < len {
+        let pos = offset + copied;
+        let sector_lba = lba + (pos / 512) as u64;
+        let sector_offset = pos % 512;
+        let chunk = core::cmp::min(512 - sector_offset, len - copied);
+
+        retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.read_sector(sector_lba, &mut sector))?;
+        out[copied..copied + chunk].copy_from_slice(&sector[sector_offset..sector_offset + chunk]);
+        copied += chunk;
+    }
+
+    Ok(())
+}
+
 pub fn read_block_ahci(
     port: &HbaPort,
     super_block: &SuperBlock,
@@ -151,9 +198,8 @@ pub fn read_block_ahci(
     
     println!("Read lba: {}", lba);
     
-    if !ahci_read(port, lba, sectors_per_block as u32, buffer.as_mut_ptr()) {
-        return Err(BlockError::ReadFailed);
-    }
+    let driver = AhciDriver { port: port as *const HbaPort as *mut HbaPort };
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.read_sector(lba, &mut buffer))?;
     
     buffer.truncate(super_block.block_size as usize);
     Ok(buffer)
@@ -207,9 +253,8 @@ pub fn write_block_ahci(
     
     println!("Write lba: {}", lba);
     
-    if !ahci_write(port, lba, sectors_per_block as u32, buffer.as_ptr()) {
-        return Err(BlockError::WriteFailed);
-    }
+    let driver = AhciDriver { port: port as *const HbaPort as *mut HbaPort };
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.write_sector(lba, &buffer))?;
     
     Ok(())
 }
\ No newline at end of file
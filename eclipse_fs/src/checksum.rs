@@ -0,0 +1,59 @@
+//! Checksum routines shared by the on-disk integrity checks
+
+/// Reflected CRC-32 polynomial (IEEE 802.3)
+const CRC32_POLY: u32 = 0xEDB88320;
+
+const CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+const fn build_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Standard CRC-32, `crc32(b"123456789") == 0xCBF43926`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continues a CRC-32 over `data` so a checksum can be built up in pieces.
+/// Passing 0 as `crc` starts a new checksum.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const ADLER_MOD: u32 = 65521;
+/// Largest block that can be summed before `b` would overflow a u32
+const ADLER_NMAX: usize = 5552;
+
+/// Adler-32, cheaper than CRC-32 but weaker on short inputs.
+/// `adler32(b"Wikipedia") == 0x11E60398`.
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    for chunk in data.chunks(ADLER_NMAX) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+
+    (b << 16) | a
+}
@@ -1,8 +1,8 @@
 use crate::inodes::{InodeManager, InodeError};
+use crate::permissions::{check_access, PERM_WRITE};
 use alloc::vec::Vec;
 use eclipse_framebuffer::println;
 
-#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct DirectoryEntry {
     pub inode_number: u16,
@@ -11,6 +11,9 @@ pub struct DirectoryEntry {
 }
 
 impl DirectoryEntry {
+    /// Bytes an entry takes in a directory block
+    pub const SIZE: usize = 260;
+
     pub fn new(inode_number: u16, name: &[u8]) -> Self {
         let mut entry = DirectoryEntry {
             inode_number,
@@ -21,32 +24,47 @@ impl DirectoryEntry {
         entry
     }
 
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    /// Serializes the on-disk layout: inode number (little endian), the name, its
+    /// length and one pad byte.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let ptr = self as *const DirectoryEntry as *const u8;
-        unsafe {
-            Vec::from(core::slice::from_raw_parts(ptr, core::mem::size_of::<DirectoryEntry>()))
-        }
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.inode_number.to_le_bytes());
+        bytes.extend_from_slice(&self.name);
+        bytes.push(self.name_len);
+        bytes.push(0);
+        bytes
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, InodeError> {
-        if bytes.len() < core::mem::size_of::<DirectoryEntry>() {
+        if bytes.len() < Self::SIZE {
             return Err(InodeError::ReadFailed);
         }
-        unsafe {
-            let mut entry = DirectoryEntry {
-                inode_number: 0,
-                name: [0u8; 256],
-                name_len: 0,
-            };
-            core::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut entry as *mut _ as *mut u8, core::mem::size_of::<DirectoryEntry>());
-            Ok(entry)
-        }
+        let mut name = [0u8; 256];
+        name.copy_from_slice(&bytes[2..258]);
+        Ok(DirectoryEntry {
+            inode_number: u16::from_le_bytes([bytes[0], bytes[1]]),
+            name,
+            name_len: bytes[258],
+        })
     }
 }
 
 pub struct DirectoryManager;
 
 impl DirectoryManager {
+    /// Inode of the root directory `write_eclipse_fs` created.
+    /// `NotFound` on filesystems formatted before there was one.
+    pub fn root(inode_manager: &InodeManager) -> Result<u16, InodeError> {
+        match inode_manager.super_block.root_inode {
+            0 => Err(InodeError::NotFound),
+            root => Ok(root),
+        }
+    }
+
     pub fn create_directory(
         inode_manager: &mut InodeManager,
     ) -> Result<u16, InodeError> {
@@ -55,26 +73,28 @@ impl DirectoryManager {
         Ok(inode_index)
     }
 
+    /// Appends an entry for `target_inode`, failing if `name` is already taken.
     pub fn add_entry(
         inode_manager: &mut InodeManager,
         dir_inode_index: u16,
         name: &[u8],
         target_inode: u16,
     ) -> Result<(), InodeError> {
+        check_access(&inode_manager.read_inode(dir_inode_index)?, PERM_WRITE)?;
         let entry = DirectoryEntry::new(target_inode, name);
-        let entry_bytes = entry.to_bytes();
-        
-        let mut dir_inode = inode_manager.read_inode(dir_inode_index)?;
-        dir_inode.size += entry_bytes.len() as u64;
-        
+        let mut entries = Self::read_entries(inode_manager, dir_inode_index)?;
+        if entries.iter().any(|existing| existing.name() == entry.name()) {
+            return Err(InodeError::AlreadyExists);
+        }
+
         println!("Adding entry '{}' -> inode {} to directory {}", 
             core::str::from_utf8(name).unwrap_or("invalid_utf8"),
             target_inode, 
             dir_inode_index
         );
-        
-        inode_manager.write_inode(dir_inode_index, dir_inode)?;
-        Ok(())
+
+        entries.push(entry);
+        Self::write_entries(inode_manager, dir_inode_index, &entries)
     }
 
     pub fn find_entry(
@@ -84,7 +104,7 @@ impl DirectoryManager {
     ) -> Result<Option<u16>, InodeError> {
         let dir_inode = inode_manager.read_inode(dir_inode_index)?;
         let block_size = inode_manager.super_block.block_size as usize;
-        let entries_per_block = block_size / core::mem::size_of::<DirectoryEntry>();
+        let entries_per_block = block_size / DirectoryEntry::SIZE;
         
         println!("Searching for '{}' in directory {}", 
             core::str::from_utf8(name).unwrap_or("invalid_utf8"),
@@ -104,8 +124,8 @@ impl DirectoryManager {
             )?;
             
             for entry_idx in 0..entries_per_block {
-                let offset = entry_idx * core::mem::size_of::<DirectoryEntry>();
-                if offset + core::mem::size_of::<DirectoryEntry>() > block_data.len() {
+                let offset = entry_idx * DirectoryEntry::SIZE;
+                if offset + DirectoryEntry::SIZE > block_data.len() {
                     break;
                 }
                 
@@ -137,7 +157,7 @@ impl DirectoryManager {
     ) -> Result<Vec<(u16, Vec<u8>)>, InodeError> {
         let dir_inode = inode_manager.read_inode(dir_inode_index)?;
         let block_size = inode_manager.super_block.block_size as usize;
-        let entries_per_block = block_size / core::mem::size_of::<DirectoryEntry>();
+        let entries_per_block = block_size / DirectoryEntry::SIZE;
         let mut entries = Vec::new();
         
         println!("Listing directory {}", dir_inode_index);
@@ -155,8 +175,8 @@ impl DirectoryManager {
             )?;
             
             for entry_idx in 0..entries_per_block {
-                let offset = entry_idx * core::mem::size_of::<DirectoryEntry>();
-                if offset + core::mem::size_of::<DirectoryEntry>() > block_data.len() {
+                let offset = entry_idx * DirectoryEntry::SIZE;
+                if offset + DirectoryEntry::SIZE > block_data.len() {
                     break;
                 }
                 
@@ -166,9 +186,11 @@ impl DirectoryManager {
                     let name_slice = &entry.name[..entry.name_len as usize];
                     let name_vec = name_slice.to_vec();
                     entries.push((entry.inode_number, name_vec.clone()));
-                    println!("  {} (inode {})", 
+                    let mtime = inode_manager.read_inode(entry.inode_number).map_or(0, |inode| inode.mtime);
+                    println!("  {} (inode {}, modified {})", 
                         core::str::from_utf8(name_slice).unwrap_or("invalid_utf8"),
-                        entry.inode_number
+                        entry.inode_number,
+                        mtime
                     );
                 }
             }
@@ -177,4 +199,137 @@ impl DirectoryManager {
         println!("Directory contains {} entries", entries.len());
         Ok(entries)
     }
+
+    /// Removes the entry called `name` and compacts the directory.
+    pub fn remove_entry(
+        inode_manager: &mut InodeManager,
+        dir_inode_index: u16,
+        name: &[u8],
+    ) -> Result<(), InodeError> {
+        check_access(&inode_manager.read_inode(dir_inode_index)?, PERM_WRITE)?;
+        let mut entries = Self::read_entries(inode_manager, dir_inode_index)?;
+        let Some(pos) = entries.iter().position(|entry| entry.name() == name) else {
+            return Err(InodeError::NotFound);
+        };
+
+        let removed = entries.remove(pos);
+        println!("Removing entry '{}' (inode {}) from directory {}",
+            core::str::from_utf8(name).unwrap_or("invalid_utf8"),
+            removed.inode_number,
+            dir_inode_index
+        );
+
+        Self::write_entries(inode_manager, dir_inode_index, &entries)
+    }
+
+    /// Renames the entry `old` to `new`, failing if `new` is already taken.
+    pub fn rename_entry(
+        inode_manager: &mut InodeManager,
+        dir_inode_index: u16,
+        old: &[u8],
+        new: &[u8],
+    ) -> Result<(), InodeError> {
+        check_access(&inode_manager.read_inode(dir_inode_index)?, PERM_WRITE)?;
+        let mut entries = Self::read_entries(inode_manager, dir_inode_index)?;
+        let Some(pos) = entries.iter().position(|entry| entry.name() == old) else {
+            return Err(InodeError::NotFound);
+        };
+        if old != new && entries.iter().any(|entry| entry.name() == new) {
+            return Err(InodeError::AlreadyExists);
+        }
+
+        println!("Renaming '{}' to '{}' in directory {}",
+            core::str::from_utf8(old).unwrap_or("invalid_utf8"),
+            core::str::from_utf8(new).unwrap_or("invalid_utf8"),
+            dir_inode_index
+        );
+
+        entries[pos] = DirectoryEntry::new(entries[pos].inode_number, new);
+        Self::write_entries(inode_manager, dir_inode_index, &entries)
+    }
+
+    /// Every used entry in the direct blocks of a directory.
+    fn read_entries(
+        inode_manager: &InodeManager,
+        dir_inode_index: u16,
+    ) -> Result<Vec<DirectoryEntry>, InodeError> {
+        let dir_inode = inode_manager.read_inode(dir_inode_index)?;
+        let entry_size = DirectoryEntry::SIZE;
+        let mut entries = Vec::new();
+
+        for block_idx in 0..12 {
+            if dir_inode.direct_blocks[block_idx] == 0 {
+                break;
+            }
+
+            let block_data = crate::block_io::read_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &inode_manager.bitmap,
+                dir_inode.direct_blocks[block_idx],
+            )?;
+
+            for chunk in block_data.chunks_exact(entry_size) {
+                let entry = DirectoryEntry::from_bytes(chunk)?;
+                if entry.inode_number != 0 {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Writes `entries` packed from the first block, allocating blocks as they fill up
+    /// and freeing the ones no longer needed. The first block is always kept.
+    fn write_entries(
+        inode_manager: &mut InodeManager,
+        dir_inode_index: u16,
+        entries: &[DirectoryEntry],
+    ) -> Result<(), InodeError> {
+        let mut dir_inode = inode_manager.read_inode(dir_inode_index)?;
+        let block_size = inode_manager.super_block.block_size as usize;
+        let entry_size = DirectoryEntry::SIZE;
+        let entries_per_block = block_size / entry_size;
+        let blocks_needed = entries.len().div_ceil(entries_per_block).max(1);
+        if blocks_needed > dir_inode.direct_blocks.len() {
+            return Err(InodeError::FileTooLarge);
+        }
+
+        for block_idx in 0..12 {
+            let mut block = dir_inode.direct_blocks[block_idx];
+
+            if block_idx >= blocks_needed {
+                if block != 0 {
+                    inode_manager.bitmap.free_block(block)?;
+                    dir_inode.direct_blocks[block_idx] = 0;
+                }
+                continue;
+            }
+
+            if block == 0 {
+                block = inode_manager.bitmap.allocate_block()?;
+                dir_inode.direct_blocks[block_idx] = block;
+            }
+
+            let mut block_data = alloc::vec![0u8; block_size];
+            let start = core::cmp::min(block_idx * entries_per_block, entries.len());
+            let end = core::cmp::min(start + entries_per_block, entries.len());
+            for (slot, entry) in entries[start..end].iter().enumerate() {
+                let offset = slot * entry_size;
+                block_data[offset..offset + entry_size].copy_from_slice(&entry.to_bytes());
+            }
+
+            crate::block_io::write_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &mut inode_manager.bitmap,
+                block,
+                &block_data,
+            )?;
+        }
+
+        dir_inode.size = (entries.len() * entry_size) as u64;
+        inode_manager.write_inode(dir_inode_index, dir_inode)
+    }
 }
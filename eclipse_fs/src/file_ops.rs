@@ -1,5 +1,6 @@
 use crate::block_io::{read_block, write_block};
 use crate::inodes::{InodeManager, InodeError};
+use crate::permissions::{check_access, PERM_READ, PERM_WRITE};
 
 use alloc::{vec, vec::Vec};
 use eclipse_framebuffer::println;
@@ -8,13 +9,32 @@ pub fn create_file(
     inode_manager: &mut InodeManager,
     data: &[u8],
 ) -> Result<u16, InodeError> {
-    let inode_index = inode_manager.create_inode()?;
     let block_size = inode_manager.super_block.block_size as usize;
+    let blocks_per_indirect = block_size / 8;
+    
+    // 12 direct blocks plus one indirect block worth of pointers
+    let blocks_needed = data.len().div_ceil(block_size);
+    if blocks_needed > 12 + blocks_per_indirect {
+        return Err(InodeError::FileTooLarge);
+    }
+    
+    // New files land in the root directory, so creating one needs write access to it
+    let root = inode_manager.super_block.root_inode;
+    if root != 0 {
+        check_access(&inode_manager.read_inode(root)?, PERM_WRITE)?;
+    }
+    
+    let inode_index = inode_manager.create_inode()?;
     
     println!("Creating file: inode {}, size {} bytes", inode_index, data.len());
     
     let mut inode = inode_manager.read_inode(inode_index)?;
     inode.size = data.len() as u64;
+    inode.ctime = crate::now();
+    inode.mtime = inode.ctime;
+    
+    // Pointers are collected here and the indirect block is written once at the end
+    let mut indirect_data: Option<Vec<u8>> = None;
     
     for (i, chunk) in data.chunks(block_size).enumerate() {
         if i < 12 {
@@ -29,11 +49,34 @@ pub fn create_file(
             )?;
             println!("File block {} written to direct block {}", i, block);
         } else {
-            handle_indirect_block(inode_manager, &mut inode, i, chunk)?;
-            println!("File block {} written to indirect block", i);
+            let data_block = inode_manager.bitmap.allocate_block()?;
+            write_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &mut inode_manager.bitmap,
+                data_block,
+                chunk,
+            )?;
+            
+            let offset = (i - 12) * 8;
+            let table = indirect_data.get_or_insert_with(|| vec![0u8; block_size]);
+            table[offset..offset + 8].copy_from_slice(&data_block.to_le_bytes());
+            println!("File block {} written to indirect data block {}", i, data_block);
         }
     }
     
+    if let Some(table) = indirect_data {
+        inode.indirect_block = inode_manager.bitmap.allocate_block()?;
+        write_block(
+            inode_manager.drive,
+            &inode_manager.super_block,
+            &mut inode_manager.bitmap,
+            inode.indirect_block,
+            &table,
+        )?;
+        println!("Indirect block written at {}", inode.indirect_block);
+    }
+    
     inode_manager.write_inode(inode_index, inode)?;
     println!("File created successfully at inode {}", inode_index);
     
@@ -45,6 +88,7 @@ pub fn read_file(
     inode_index: u16,
 ) -> Result<Vec<u8>, InodeError> {
     let inode = inode_manager.read_inode(inode_index)?;
+    check_access(&inode, PERM_READ)?;
     let block_size = inode_manager.super_block.block_size as usize;
     let mut file_data = Vec::with_capacity(inode.size as usize);
     
@@ -93,7 +137,7 @@ pub fn list_files(
     for (i, inode) in inode_manager.inode_table.inodes.iter().enumerate() {
         if inode.size > 0 {
             file_inodes.push(i as u16);
-            println!("Found file: inode {}, size {} bytes", i, inode.size);
+            println!("Found file: inode {}, size {} bytes, modified {}", i, inode.size, inode.mtime);
         }
     }
     
@@ -105,6 +149,7 @@ pub fn delete_file(
     inode_index: u16,
 ) -> Result<(), InodeError> {
     let inode = inode_manager.read_inode(inode_index)?;
+    check_access(&inode, PERM_WRITE)?;
     
     println!("Deleting file: inode {}, size {} bytes", inode_index, inode.size);
     
@@ -131,53 +176,196 @@ pub fn delete_file(
     Ok(())
 }
 
-fn handle_indirect_block(
+/// Adds `data` to the end of a file, filling the partly used last block before
+/// allocating new ones.
+pub fn append(
     inode_manager: &mut InodeManager,
-    inode: &mut crate::inodes::Inode,
-    block_index: usize,
+    inode_index: u16,
     data: &[u8],
 ) -> Result<(), InodeError> {
+    let mut inode = inode_manager.read_inode(inode_index)?;
+    check_access(&inode, PERM_WRITE)?;
     let block_size = inode_manager.super_block.block_size as usize;
     let blocks_per_indirect = block_size / 8;
     
-    if inode.indirect_block == 0 {
-        inode.indirect_block = inode_manager.bitmap.allocate_block()?;
-        println!("Allocated indirect block at {}", inode.indirect_block);
+    let new_size = inode.size as usize + data.len();
+    if new_size.div_ceil(block_size) > 12 + blocks_per_indirect {
+        return Err(InodeError::FileTooLarge);
     }
     
-    let indirect_index = (block_index - 12) % blocks_per_indirect;
-    let indirect_offset = indirect_index * 8;
+    println!("Appending to file: inode {}, {} bytes at offset {}", inode_index, data.len(), inode.size);
     
-    let data_block = inode_manager.bitmap.allocate_block()?;
-    write_block(
-        inode_manager.drive,
-        &inode_manager.super_block,
-        &mut inode_manager.bitmap,
-        data_block,
-        data,
-    )?;
+    let mut indirect_data = if inode.indirect_block != 0 {
+        Some(read_block(
+            inode_manager.drive,
+            &inode_manager.super_block,
+            &inode_manager.bitmap,
+            inode.indirect_block,
+        )?)
+    } else {
+        None
+    };
+    let mut indirect_dirty = false;
     
-    let mut indirect_data = vec![0u8; block_size];
-    let ptr = &data_block as *const u64 as *const u8;
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            ptr,
-            indirect_data.as_mut_ptr().add(indirect_offset),
-            8,
-        );
+    let mut pos = inode.size as usize;
+    let mut written = 0;
+    while written < data.len() {
+        let index = pos / block_size;
+        let offset = pos % block_size;
+        let chunk = core::cmp::min(block_size - offset, data.len() - written);
+        let bytes = &data[written..written + chunk];
+        
+        if offset != 0 {
+            // The last block is partly used, merge the new bytes into it
+            let block = block_pointer(&inode, indirect_data.as_deref(), index);
+            if block == 0 {
+                return Err(InodeError::InvalidInode);
+            }
+            let mut block_data = read_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &inode_manager.bitmap,
+                block,
+            )?;
+            block_data[offset..offset + chunk].copy_from_slice(bytes);
+            write_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &mut inode_manager.bitmap,
+                block,
+                &block_data,
+            )?;
+            println!("File block {} extended in block {}", index, block);
+        } else {
+            let block = inode_manager.bitmap.allocate_block()?;
+            write_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &mut inode_manager.bitmap,
+                block,
+                bytes,
+            )?;
+            
+            if index < 12 {
+                inode.direct_blocks[index] = block;
+            } else {
+                let entry = (index - 12) * 8;
+                let table = indirect_data.get_or_insert_with(|| vec![0u8; block_size]);
+                table[entry..entry + 8].copy_from_slice(&block.to_le_bytes());
+                indirect_dirty = true;
+            }
+            println!("File block {} written to block {}", index, block);
+        }
+        
+        pos += chunk;
+        written += chunk;
     }
     
-    write_block(
-        inode_manager.drive,
-        &inode_manager.super_block,
-        &mut inode_manager.bitmap,
-        inode.indirect_block,
-        &indirect_data,
-    )?;
+    if let Some(table) = indirect_data.filter(|_| indirect_dirty) {
+        if inode.indirect_block == 0 {
+            inode.indirect_block = inode_manager.bitmap.allocate_block()?;
+        }
+        write_block(
+            inode_manager.drive,
+            &inode_manager.super_block,
+            &mut inode_manager.bitmap,
+            inode.indirect_block,
+            &table,
+        )?;
+    }
+    
+    inode.size = new_size as u64;
+    inode.mtime = crate::now();
+    inode_manager.write_inode(inode_index, inode)?;
     
     Ok(())
 }
 
+/// Shrinks a file to `new_len` bytes and returns the blocks past it to the bitmap.
+/// Files can't be grown this way, use `append`.
+pub fn truncate(
+    inode_manager: &mut InodeManager,
+    inode_index: u16,
+    new_len: u64,
+) -> Result<(), InodeError> {
+    let mut inode = inode_manager.read_inode(inode_index)?;
+    check_access(&inode, PERM_WRITE)?;
+    if new_len > inode.size {
+        return Err(InodeError::OutOfBounds);
+    }
+    
+    let block_size = inode_manager.super_block.block_size as u64;
+    let old_blocks = inode.size.div_ceil(block_size) as usize;
+    let keep_blocks = new_len.div_ceil(block_size) as usize;
+    
+    println!("Truncating file: inode {}, {} -> {} bytes", inode_index, inode.size, new_len);
+    
+    for i in keep_blocks..old_blocks.min(12) {
+        if inode.direct_blocks[i] != 0 {
+            inode_manager.bitmap.free_block(inode.direct_blocks[i])?;
+            inode.direct_blocks[i] = 0;
+        }
+    }
+    
+    if inode.indirect_block != 0 && old_blocks > 12 {
+        let mut table = read_block(
+            inode_manager.drive,
+            &inode_manager.super_block,
+            &inode_manager.bitmap,
+            inode.indirect_block,
+        )?;
+        
+        for i in keep_blocks.max(12)..old_blocks {
+            let entry = (i - 12) * 8;
+            if entry + 8 > table.len() {
+                break;
+            }
+            let block = table_entry(&table, i - 12);
+            if block != 0 {
+                inode_manager.bitmap.free_block(block)?;
+                table[entry..entry + 8].fill(0);
+            }
+        }
+        
+        if keep_blocks <= 12 {
+            inode_manager.bitmap.free_block(inode.indirect_block)?;
+            inode.indirect_block = 0;
+        } else {
+            write_block(
+                inode_manager.drive,
+                &inode_manager.super_block,
+                &mut inode_manager.bitmap,
+                inode.indirect_block,
+                &table,
+            )?;
+        }
+    }
+    
+    inode.size = new_len;
+    inode.mtime = crate::now();
+    inode_manager.write_inode(inode_index, inode)?;
+    
+    Ok(())
+}
+
+/// Block number stored in slot `index` of an indirect table.
+fn table_entry(table: &[u8], index: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&table[index * 8..index * 8 + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Disk block holding file block `index`, 0 if it isn't allocated.
+fn block_pointer(inode: &crate::inodes::Inode, indirect_data: Option<&[u8]>, index: usize) -> u64 {
+    if index < 12 {
+        return inode.direct_blocks[index];
+    }
+    match indirect_data {
+        Some(table) if (index - 12) * 8 + 8 <= table.len() => table_entry(table, index - 12),
+        _ => 0,
+    }
+}
+
 fn read_indirect_blocks(
     inode_manager: &InodeManager,
     inode: &crate::inodes::Inode,
@@ -194,9 +382,8 @@ fn read_indirect_blocks(
         inode.indirect_block,
     )?;
     
-    for i in 12..blocks_to_read {
-        let indirect_idx = (i - 12) % blocks_per_indirect;
-        let offset = indirect_idx * 8;
+    for i in 12..blocks_to_read.min(12 + blocks_per_indirect) {
+        let offset = (i - 12) * 8;
         
         if offset + 8 > indirect_data.len() {
             break;
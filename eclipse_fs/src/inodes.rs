@@ -5,6 +5,7 @@ use alloc::vec::Vec;
 use crate::super_block::SuperBlock;
 use crate::bitmap::{BlockBitmap, BitmapError};
 use crate::block_io::{read_block, write_block, BlockError};
+use crate::permissions::{current_gid, current_uid, DEFAULT_MODE, ROOT_UID};
 use eclipse_framebuffer::println;
 
 #[derive(Debug)]
@@ -13,6 +14,13 @@ pub enum InodeError {
     ReadFailed,
     WriteFailed,
     InvalidInode,
+    PermissionDenied,
+    /// More data than 12 direct blocks and one indirect block can address
+    FileTooLarge,
+    /// No directory entry with that name
+    NotFound,
+    /// A directory entry with that name already exists
+    AlreadyExists,
     BitmapError(BitmapError),
     BlockError(BlockError),
 }
@@ -29,22 +37,43 @@ impl From<BlockError> for InodeError {
     }
 }
 
-#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct Inode {
     pub size: u64,
     pub direct_blocks: [u64; 12],
     pub indirect_block: u64,
     pub double_indirect_block: u64,
+    pub mode: u16,
+    pub uid: u16,
+    pub gid: u16,
+    /// Pads the permission fields to 8 bytes on disk, always 0
+    _reserved: u16,
+    /// Seconds since the Unix epoch of the last write, 0 before version 3
+    pub mtime: u64,
+    /// Seconds since the Unix epoch the file was created, 0 before version 3
+    pub ctime: u64,
 }
 
 impl Inode {
+    /// Version 1 inodes end after the block pointers
+    pub const V1_SIZE: usize = 120;
+    /// Version 2 added `mode`, `uid`, `gid` and a reserved u16
+    pub const V2_SIZE: usize = 128;
+    /// Version 3 added `mtime` and `ctime`
+    pub const V3_SIZE: usize = 144;
+
     pub fn new() -> Self {
         Inode {
             size: 0,
             direct_blocks: [0; 12],
             indirect_block: 0,
             double_indirect_block: 0,
+            mode: DEFAULT_MODE,
+            uid: current_uid(),
+            gid: current_gid(),
+            _reserved: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
@@ -52,22 +81,69 @@ impl Inode {
         self.size
     }
 
+    /// Bytes an inode takes on a filesystem of the given superblock version.
+    pub fn disk_size(version: u8) -> usize {
+        match version {
+            0 | 1 => Self::V1_SIZE,
+            2 => Self::V2_SIZE,
+            _ => Self::V3_SIZE,
+        }
+    }
+
+    /// Serializes the newest layout, little endian. Older layouts are a prefix of it.
     pub fn to_bytes(&self) -> Vec<u8> {
-        let ptr = self as *const Inode as *const u8;
-        unsafe {
-            Vec::from(core::slice::from_raw_parts(ptr, core::mem::size_of::<Inode>()))
+        let mut bytes = Vec::with_capacity(Self::V3_SIZE);
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        for block in self.direct_blocks {
+            bytes.extend_from_slice(&block.to_le_bytes());
         }
+        bytes.extend_from_slice(&self.indirect_block.to_le_bytes());
+        bytes.extend_from_slice(&self.double_indirect_block.to_le_bytes());
+        bytes.extend_from_slice(&self.mode.to_le_bytes());
+        bytes.extend_from_slice(&self.uid.to_le_bytes());
+        bytes.extend_from_slice(&self.gid.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.to_le_bytes());
+        bytes.extend_from_slice(&self.ctime.to_le_bytes());
+        bytes
     }
 
+    /// Parses any layout, picked by the length of `bytes`. Version 1 inodes come back
+    /// owned by root with the default mode, fields a layout doesn't have are 0.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, InodeError> {
-        if bytes.len() < core::mem::size_of::<Inode>() {
+        if bytes.len() < Self::V1_SIZE {
             return Err(InodeError::ReadFailed);
         }
-        unsafe {
-            let mut inode = Inode::new();
-            core::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut inode as *mut _ as *mut u8, core::mem::size_of::<Inode>());
-            Ok(inode)
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        let mut direct_blocks = [0u64; 12];
+        for (i, block) in direct_blocks.iter_mut().enumerate() {
+            *block = u64_at(8 + i * 8);
+        }
+
+        let mut inode = Inode {
+            size: u64_at(0),
+            direct_blocks,
+            indirect_block: u64_at(104),
+            double_indirect_block: u64_at(112),
+            mode: DEFAULT_MODE,
+            uid: ROOT_UID,
+            gid: ROOT_UID,
+            _reserved: 0,
+            mtime: 0,
+            ctime: 0,
+        };
+        if bytes.len() >= Self::V2_SIZE {
+            inode.mode = u16_at(120);
+            inode.uid = u16_at(122);
+            inode.gid = u16_at(124);
+        }
+        if bytes.len() >= Self::V3_SIZE {
+            inode.mtime = u64_at(128);
+            inode.ctime = u64_at(136);
         }
+        Ok(inode)
     }
 }
 
@@ -85,7 +161,7 @@ impl InodeTable {
 
     pub fn from_disk(drive: usize, super_block: &SuperBlock) -> Result<Self, InodeError> {
         let mut inodes = Vec::new();
-        let inode_size = core::mem::size_of::<Inode>() as u64;
+        let inode_size = super_block.inode_size();
         let inodes_per_block = super_block.block_size / inode_size;
         
         for i in 0..super_block.inode_table_blocks {
@@ -94,8 +170,8 @@ impl InodeTable {
             
             for j in 0..inodes_per_block {
                 let offset = (j * inode_size) as usize;
-                if offset + core::mem::size_of::<Inode>() <= block_data.len() {
-                    let inode = Inode::from_bytes(&block_data[offset..])?;
+                if offset + inode_size as usize <= block_data.len() {
+                    let inode = Inode::from_bytes(&block_data[offset..offset + inode_size as usize])?;
                     inodes.push(inode);
                 }
             }
@@ -105,7 +181,7 @@ impl InodeTable {
     }
 
     pub fn to_disk(&self, drive: usize, super_block: &SuperBlock, bitmap: &mut BlockBitmap) -> Result<(), InodeError> {
-        let inode_size = core::mem::size_of::<Inode>() as u64;
+        let inode_size = super_block.inode_size();
         let inodes_per_block = super_block.block_size / inode_size;
         
         for (block_idx, block) in (0..super_block.inode_table_blocks).enumerate() {
@@ -114,9 +190,9 @@ impl InodeTable {
             for j in 0..inodes_per_block {
                 let inode_idx = block_idx as u64 * inodes_per_block + j;
                 if (inode_idx as usize) < self.inodes.len() {
-                    block_data.extend_from_slice(&self.inodes[inode_idx as usize].to_bytes());
+                    block_data.extend_from_slice(&self.inodes[inode_idx as usize].to_bytes()[..inode_size as usize]);
                 } else {
-                    block_data.extend_from_slice(&[0u8; core::mem::size_of::<Inode>()]);
+                    block_data.resize(block_data.len() + inode_size as usize, 0);
                 }
             }
             
@@ -204,8 +280,11 @@ pub struct DirectoryHandle {
 
 impl fmt::Display for Inode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Inode(size: {}, direct_blocks: {:?}, indirect_block: {}, double_indirect_block: {})",
+        write!(f, "Inode(size: {}, mode: {:o}, uid: {}, gid: {}, direct_blocks: {:?}, indirect_block: {}, double_indirect_block: {})",
             self.size,
+            self.mode,
+            self.uid,
+            self.gid,
             self.direct_blocks,
             self.indirect_block,
             self.double_indirect_block)
@@ -0,0 +1,177 @@
+//! Write-ahead log for filesystem metadata
+//! Changes are copied into the reserved region, committed in a single header sector,
+//! then written to their real location. A committed header found at mount is replayed.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use eclipse_framebuffer::println;
+use ide::ide_get_drive;
+
+use crate::{IdeDriver, StorageDriver};
+use crate::block_io::BlockError;
+use crate::retry::{IO_RETRY_ATTEMPTS, block_failure, retry_io};
+use crate::super_block::SuperBlock;
+
+/// The header lives right after the superblock in block 0 so it can be found
+/// without trusting the superblock it may be about to repair
+const JOURNAL_HEADER_LBA: u64 = 2;
+const JOURNAL_MAGIC: u32 = 0x4C4A_4345; // "ECJL"
+
+const JOURNAL_EMPTY: u32 = 0;
+const JOURNAL_COMMITTED: u32 = 1;
+
+const JOURNAL_HEADER_SIZE: usize = 16;
+const JOURNAL_RECORD_SIZE: usize = 24;
+pub const JOURNAL_MAX_RECORDS: usize = (512 - JOURNAL_HEADER_SIZE) / JOURNAL_RECORD_SIZE;
+
+/// Sectors copied per request during replay
+const REPLAY_CHUNK_SECTORS: u32 = 64;
+
+#[derive(Debug)]
+pub enum JournalError {
+    InvalidDrive,
+    TooManyRecords,
+    /// The changes don't fit in the reserved region
+    TooLarge,
+    BlockError(BlockError),
+}
+
+impl From<BlockError> for JournalError {
+    fn from(err: BlockError) -> Self {
+        JournalError::BlockError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct JournalRecord {
+    target_lba: u64,
+    journal_lba: u64,
+    sectors: u32,
+}
+
+fn open(drive: usize) -> Result<IdeDriver, JournalError> {
+    Ok(IdeDriver { drive: ide_get_drive(drive).ok_or(JournalError::InvalidDrive)? })
+}
+
+fn read_sectors(driver: &IdeDriver, lba: u64, buffer: &mut [u8]) -> Result<(), JournalError> {
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.read_sector(lba, buffer))?;
+    Ok(())
+}
+
+fn write_sectors(driver: &IdeDriver, lba: u64, data: &[u8]) -> Result<(), JournalError> {
+    retry_io(IO_RETRY_ATTEMPTS, block_failure, || driver.write_sector(lba, data))?;
+    Ok(())
+}
+
+fn write_header(driver: &IdeDriver, state: u32, records: &[JournalRecord]) -> Result<(), JournalError> {
+    let mut header = [0u8; 512];
+    header[0..4].copy_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&state.to_le_bytes());
+    header[8..12].copy_from_slice(&(records.len() as u32).to_le_bytes());
+
+    for (i, record) in records.iter().enumerate() {
+        let offset = JOURNAL_HEADER_SIZE + i * JOURNAL_RECORD_SIZE;
+        header[offset..offset + 8].copy_from_slice(&record.target_lba.to_le_bytes());
+        header[offset + 8..offset + 16].copy_from_slice(&record.journal_lba.to_le_bytes());
+        header[offset + 16..offset + 20].copy_from_slice(&record.sectors.to_le_bytes());
+    }
+
+    write_sectors(driver, JOURNAL_HEADER_LBA, &header)
+}
+
+/// Records of a committed journal, `None` if there is nothing to replay.
+fn read_header(driver: &IdeDriver) -> Result<Option<Vec<JournalRecord>>, JournalError> {
+    let mut header = [0u8; 512];
+    read_sectors(driver, JOURNAL_HEADER_LBA, &mut header)?;
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let state = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let count = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+    if magic != JOURNAL_MAGIC || state != JOURNAL_COMMITTED || count > JOURNAL_MAX_RECORDS {
+        return Ok(None);
+    }
+
+    let records = (0..count)
+        .map(|i| {
+            let offset = JOURNAL_HEADER_SIZE + i * JOURNAL_RECORD_SIZE;
+            JournalRecord {
+                target_lba: u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap()),
+                journal_lba: u64::from_le_bytes(header[offset + 8..offset + 16].try_into().unwrap()),
+                sectors: u32::from_le_bytes(header[offset + 16..offset + 20].try_into().unwrap()),
+            }
+        })
+        .collect();
+
+    Ok(Some(records))
+}
+
+fn apply(driver: &IdeDriver, records: &[JournalRecord]) -> Result<(), JournalError> {
+    let mut buffer = vec![0u8; REPLAY_CHUNK_SECTORS as usize * 512];
+
+    for record in records {
+        let mut done = 0;
+        while done < record.sectors {
+            let count = core::cmp::min(REPLAY_CHUNK_SECTORS, record.sectors - done);
+            let chunk = &mut buffer[..count as usize * 512];
+            read_sectors(driver, record.journal_lba + done as u64, chunk)?;
+            write_sectors(driver, record.target_lba + done as u64, chunk)?;
+            done += count;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks the journal empty, anything left in it is discarded.
+pub fn journal_clear(drive: usize) -> Result<(), JournalError> {
+    let driver = open(drive)?;
+    write_header(&driver, JOURNAL_EMPTY, &[])
+}
+
+/// Writes each `(lba, data)` pair through the journal.
+/// The data is staged in the reserved region described by `super_block`, committed,
+/// written in place and the journal is cleared again.
+pub fn journal_write(drive: usize, super_block: &SuperBlock, changes: &[(u64, &[u8])]) -> Result<(), JournalError> {
+    if changes.len() > JOURNAL_MAX_RECORDS {
+        return Err(JournalError::TooManyRecords);
+    }
+
+    let driver = open(drive)?;
+    let sectors_per_block = super_block.block_size / 512;
+    let journal_start = super_block.reserved_start * sectors_per_block;
+    let journal_end = journal_start + super_block.reserved_blocks * sectors_per_block;
+
+    let mut records = Vec::with_capacity(changes.len());
+    let mut next_lba = journal_start;
+
+    for &(target_lba, data) in changes {
+        let sectors = data.len().div_ceil(512) as u64;
+        if next_lba + sectors > journal_end {
+            println!("Journal needs {} more sectors than the reserved region has", next_lba + sectors - journal_end);
+            return Err(JournalError::TooLarge);
+        }
+
+        write_sectors(&driver, next_lba, data)?;
+        records.push(JournalRecord { target_lba, journal_lba: next_lba, sectors: sectors as u32 });
+        next_lba += sectors;
+    }
+
+    write_header(&driver, JOURNAL_COMMITTED, &records)?;
+    apply(&driver, &records)?;
+    write_header(&driver, JOURNAL_EMPTY, &[])
+}
+
+/// Finishes a committed journal left behind by an interrupted write.
+/// Returns whether anything was replayed.
+pub fn journal_replay(drive: usize) -> Result<bool, JournalError> {
+    let driver = open(drive)?;
+    let Some(records) = read_header(&driver)? else {
+        return Ok(false);
+    };
+
+    println!("Replaying {} journal records", records.len());
+    apply(&driver, &records)?;
+    write_header(&driver, JOURNAL_EMPTY, &[])?;
+    Ok(true)
+}
@@ -1,12 +1,13 @@
 #![no_std]
 extern crate alloc;
 
-use ide::{ide_read_sectors, ide_write_sectors};
+use ide::{ide_get_drive, IdeError, IdeHandle};
+use ahci::{HbaPort, ahci_read, ahci_sector_buffer, ahci_write};
 use eclipse_framebuffer::println;
 use alloc::vec;
 
 pub use super_block::SuperBlock;
-pub use block_io::{read_block, write_block, BlockError};
+pub use block_io::{read_block, read_partial, write_block, BlockError, DeviceError};
 pub use bitmap::{BlockBitmap, BitmapError};
 pub use inodes::{InodeManager, Inode};
 
@@ -16,27 +17,113 @@ mod bitmap;
 pub mod inodes;
 pub mod file_ops;
 pub mod directory;
+pub mod permissions;
+pub mod checksum;
+pub mod retry;
+pub mod journal;
+
+/// Source of the timestamps stored in inodes, unset until `set_clock`
+static mut FS_CLOCK: Option<fn() -> u64> = None;
+
+/// Gives the filesystem a clock returning seconds since the Unix epoch.
+/// Until this is called every timestamp is written as 0.
+pub fn set_clock(now: fn() -> u64) {
+    unsafe {
+        FS_CLOCK = Some(now);
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    match unsafe { FS_CLOCK } {
+        Some(clock) => clock(),
+        None => 0,
+    }
+}
+
+/// Permission bits of the root directory created by `write_eclipse_fs`
+const ROOT_DIR_MODE: u16 = 0o755;
 
 pub trait StorageDriver {
-    fn read_sector(&self, lba: u64, buffer: &mut [u8]) -> bool;
-    fn write_sector(&self, lba: u64, data: &[u8]) -> bool;
+    fn read_sector(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+    fn write_sector(&self, lba: u64, data: &[u8]) -> Result<(), BlockError>;
+}
+
+impl IdeDriver {
+    /// Same as `read_sector` but keeps the reason a read failed.
+    pub fn try_read_sector(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IdeError> {
+        self.drive.read(lba, buffer)
+    }
+
+    /// Same as `write_sector` but keeps the reason a write failed.
+    pub fn try_write_sector(&self, lba: u64, data: &[u8]) -> Result<(), IdeError> {
+        self.drive.write(lba, data)
+    }
 }
 
 pub struct IdeDriver {
-    pub drive: usize,
+    pub drive: IdeHandle,
 }
 
 impl StorageDriver for IdeDriver {
-    fn read_sector(&self, lba: u64, buffer: &mut [u8]) -> bool {
-        ide_read_sectors(self.drive, lba, buffer) == 0
+    fn read_sector(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        Ok(self.try_read_sector(lba, buffer)?)
     }
 
-    fn write_sector(&self, lba: u64, data: &[u8]) -> bool {
-        ide_write_sectors(self.drive, lba, data) == 0
+    fn write_sector(&self, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        Ok(self.try_write_sector(lba, data)?)
     }
 }
 
-fn zero_sector(drive: usize, start_block: u64, num_blocks: u64, block_size_bytes: u64) -> bool {
+/// A SATA drive on an AHCI port. Every sector goes through the port's DMA sector buffer,
+/// so callers can pass ordinary heap buffers.
+pub struct AhciDriver {
+    pub port: *mut HbaPort,
+}
+
+impl AhciDriver {
+    fn port(&self) -> &HbaPort {
+        unsafe { &*self.port }
+    }
+
+    fn sector_buffer(&self) -> Result<(u64, *mut u8), BlockError> {
+        if self.port.is_null() {
+            return Err(BlockError::InvalidDrive);
+        }
+        ahci_sector_buffer(self.port()).ok_or(BlockError::InvalidDrive)
+    }
+}
+
+impl StorageDriver for AhciDriver {
+    /// Reads `buffer.len() / 512` sectors starting at `lba`, the buffer must be a whole number of sectors.
+    fn read_sector(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if !buffer.len().is_multiple_of(512) {
+            return Err(BlockError::InvalidBlockSize);
+        }
+        let (phys, virt) = self.sector_buffer()?;
+
+        for (i, sector) in buffer.chunks_mut(512).enumerate() {
+            ahci_read(self.port(), lba + i as u64, 1, phys as *mut u8)?;
+            unsafe { core::ptr::copy_nonoverlapping(virt, sector.as_mut_ptr(), 512) };
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting at `lba`, a partial last sector is padded with zeros.
+    fn write_sector(&self, lba: u64, data: &[u8]) -> Result<(), BlockError> {
+        let (phys, virt) = self.sector_buffer()?;
+
+        for (i, sector) in data.chunks(512).enumerate() {
+            unsafe {
+                core::ptr::write_bytes(virt, 0, 512);
+                core::ptr::copy_nonoverlapping(sector.as_ptr(), virt, sector.len());
+            }
+            ahci_write(self.port(), lba + i as u64, 1, phys as *const u8)?;
+        }
+        Ok(())
+    }
+}
+
+fn zero_sector(disk: IdeHandle, start_block: u64, num_blocks: u64, block_size_bytes: u64) -> bool {
     let sector_size: u64 = 512;
     let sectors_per_block = block_size_bytes / sector_size;
     let start_sector = start_block * sectors_per_block;
@@ -45,7 +132,7 @@ fn zero_sector(drive: usize, start_block: u64, num_blocks: u64, block_size_bytes
     
     for i in 0..total_sectors {
         let sector_to_write = start_sector + i;
-        if ide_write_sectors(drive, sector_to_write, &zero_sector) != 0 {
+        if disk.write(sector_to_write, &zero_sector).is_err() {
             println!("Writing zeros failed at: {}", sector_to_write);
             return false;
         }
@@ -55,19 +142,22 @@ fn zero_sector(drive: usize, start_block: u64, num_blocks: u64, block_size_bytes
 
 pub fn write_eclipse_fs(drive: u8) {
     let drive_usize = drive as usize;
+    let Some(disk) = ide_get_drive(drive_usize) else {
+        println!("Drive {} not present", drive);
+        return;
+    };
     let super_block = SuperBlock::new(drive);
     println!("SuperBlock Layout: {}", super_block);
     
-    let sb_bytes_512 = super_block.to_bytes();
-    if ide_write_sectors(drive_usize, 1, &sb_bytes_512) != 0 {
-        println!("Failed to write superblock");
+    // Formatting discards whatever an older filesystem left in the journal
+    if let Err(e) = journal::journal_clear(drive_usize) {
+        println!("Failed to clear journal: {:?}", e);
         return;
     }
-    println!("Superblock written to disk.");
     
     println!("Initializing Inode: {}", super_block.inode_table_blocks);
     if !zero_sector(
-        drive_usize,
+        disk,
         super_block.inode_table_start,
         super_block.inode_table_blocks,
         super_block.block_size
@@ -78,7 +168,7 @@ pub fn write_eclipse_fs(drive: u8) {
     
     println!("Initializing Block Bitmap region ({} blocks)", super_block.block_bitmap_blocks);
     if !zero_sector(
-        drive_usize,
+        disk,
         super_block.block_bitmap_start,
         super_block.block_bitmap_blocks,
         super_block.block_size
@@ -89,7 +179,7 @@ pub fn write_eclipse_fs(drive: u8) {
     
     println!("Initializing Reserved region ({} blocks)", super_block.reserved_blocks);
     if !zero_sector(
-        drive_usize,
+        disk,
         super_block.reserved_start,
         super_block.reserved_blocks,
         super_block.block_size
@@ -98,9 +188,51 @@ pub fn write_eclipse_fs(drive: u8) {
     }
     println!("Reserved region initialized.");
     
+    // The root directory gets one zeroed block so listing it works straight away
+    let mut bitmap = BlockBitmap::new(&super_block);
+    let root_block = match bitmap.allocate_block() {
+        Ok(block) => block,
+        Err(e) => {
+            println!("No block for the root directory: {:?}", e);
+            return;
+        }
+    };
+    if !zero_sector(disk, root_block, 1, super_block.block_size) {
+        return;
+    }
+    
+    let mut root = Inode::new();
+    root.mode = ROOT_DIR_MODE;
+    root.direct_blocks[0] = root_block;
+    root.ctime = now();
+    root.mtime = root.ctime;
+    let inode_size = super_block.inode_size() as usize;
+    let root_offset = SuperBlock::ROOT_INODE as usize * inode_size;
+    let mut inode_block = vec![0u8; super_block.block_size as usize];
+    inode_block[root_offset..root_offset + inode_size].copy_from_slice(&root.to_bytes()[..inode_size]);
+    println!("Root directory at inode {}, block {}", SuperBlock::ROOT_INODE, root_block);
+    
+    println!("Writing superblock, bitmap and root inode through the journal...");
+    let sb_bytes_512 = super_block.to_bytes();
+    let bitmap_bytes = bitmap.to_sectors();
+    let sectors_per_block = super_block.block_size / 512;
+    let bitmap_lba = super_block.block_bitmap_start * sectors_per_block;
+    let inode_table_lba = super_block.inode_table_start * sectors_per_block;
+    if let Err(e) = journal::journal_write(
+        drive_usize,
+        &super_block,
+        &[(1, &sb_bytes_512), (bitmap_lba, &bitmap_bytes), (inode_table_lba, &inode_block)],
+    ) {
+        println!("Journaled metadata write failed: {:?}", e);
+        return;
+    }
+    println!("Superblock written to disk.");
+    println!("Free blocks: {}", bitmap.free_blocks());
+    println!("Used blocks: {}", bitmap.used_blocks());
+    
     println!("Verifying superblock read-back...");
     let mut buf = vec![0u8; 512];
-    if ide_read_sectors(drive_usize, 1, &mut buf) != 0 {
+    if disk.read(1, &mut buf).is_err() {
         println!("Failed to read superblock");
         return;
     }
@@ -110,16 +242,5 @@ pub fn write_eclipse_fs(drive: u8) {
         Err(e) => println!("Verification failed: {}", e),
     }
     
-    println!("Creating and writing bitmap...");
-    let bitmap = BlockBitmap::new(&super_block);
-    match bitmap.write_to_disk(drive_usize, &super_block) {
-        Ok(()) => {
-            println!("Bitmap written successfully");
-            println!("Free blocks: {}", bitmap.free_blocks());
-            println!("Used blocks: {}", bitmap.used_blocks());
-        },
-        Err(e) => println!("Bitmap write error: {:?}", e),
-    }
-    
     println!("Filesystem initialization complete.");
 }
\ No newline at end of file
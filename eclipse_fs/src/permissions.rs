@@ -0,0 +1,78 @@
+//! Unix style file permissions
+//! Every inode carries a `mode`, `uid` and `gid`. Access is checked against the
+//! current user, uid 0 is root and bypasses every check.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::inodes::{Inode, InodeError, InodeManager};
+
+pub const ROOT_UID: u16 = 0;
+pub const DEFAULT_MODE: u16 = 0o644;
+
+pub const PERM_READ: u16 = 0o4;
+pub const PERM_WRITE: u16 = 0o2;
+pub const PERM_EXEC: u16 = 0o1;
+
+static CURRENT_UID: AtomicU16 = AtomicU16::new(ROOT_UID);
+static CURRENT_GID: AtomicU16 = AtomicU16::new(ROOT_UID);
+
+/// Switches the user that every following filesystem call runs as.
+pub fn set_current_user(uid: u16, gid: u16) {
+    CURRENT_UID.store(uid, Ordering::Relaxed);
+    CURRENT_GID.store(gid, Ordering::Relaxed);
+}
+
+pub fn current_uid() -> u16 {
+    CURRENT_UID.load(Ordering::Relaxed)
+}
+
+pub fn current_gid() -> u16 {
+    CURRENT_GID.load(Ordering::Relaxed)
+}
+
+/// Checks that the current user has every bit of `access` (`PERM_READ`, `PERM_WRITE`, `PERM_EXEC`) on `inode`.
+pub fn check_access(inode: &Inode, access: u16) -> Result<(), InodeError> {
+    let uid = current_uid();
+    if uid == ROOT_UID {
+        return Ok(());
+    }
+
+    let granted = if uid == inode.uid {
+        (inode.mode >> 6) & 0o7
+    } else if current_gid() == inode.gid {
+        (inode.mode >> 3) & 0o7
+    } else {
+        inode.mode & 0o7
+    };
+
+    if granted & access == access {
+        Ok(())
+    } else {
+        Err(InodeError::PermissionDenied)
+    }
+}
+
+/// Changes the permission bits of an inode, only its owner or root may do this.
+pub fn chmod(inode_manager: &mut InodeManager, inode_index: u16, mode: u16) -> Result<(), InodeError> {
+    let mut inode = inode_manager.read_inode(inode_index)?;
+
+    let uid = current_uid();
+    if uid != ROOT_UID && uid != inode.uid {
+        return Err(InodeError::PermissionDenied);
+    }
+
+    inode.mode = mode & 0o777;
+    inode_manager.write_inode(inode_index, inode)
+}
+
+/// Changes the owner and group of an inode, only root may do this.
+pub fn chown(inode_manager: &mut InodeManager, inode_index: u16, uid: u16, gid: u16) -> Result<(), InodeError> {
+    if current_uid() != ROOT_UID {
+        return Err(InodeError::PermissionDenied);
+    }
+
+    let mut inode = inode_manager.read_inode(inode_index)?;
+    inode.uid = uid;
+    inode.gid = gid;
+    inode_manager.write_inode(inode_index, inode)
+}
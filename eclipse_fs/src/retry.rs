@@ -0,0 +1,105 @@
+//! Shared retry policy for disk I/O
+//! Transient failures (timeouts, aborted commands) are retried a few times,
+//! anything the drive reports as unrecoverable fails straight away
+
+use eclipse_framebuffer::println;
+use ahci::AhciError;
+use ide::IdeError;
+use crate::block_io::{BlockError, DeviceError};
+
+/// How many times a block read or write is attempted before giving up
+pub const IO_RETRY_ATTEMPTS: u32 = 3;
+
+/// Milliseconds between two attempts to give the drive time to settle
+pub const IO_RETRY_DELAY_MS: u64 = 10;
+
+/// Spins between two attempts while no clock has been set
+const IO_RETRY_FALLBACK_SPINS: usize = 100_000;
+
+static mut RETRY_CLOCK: Option<fn() -> u64> = None;
+
+/// Gives the retry backoff a clock returning milliseconds since boot.
+/// Until this is called the backoff is a fixed spin.
+pub fn set_retry_clock(now_ms: fn() -> u64) {
+    unsafe {
+        RETRY_CLOCK = Some(now_ms);
+    }
+}
+
+fn retry_delay() {
+    match unsafe { RETRY_CLOCK } {
+        Some(clock) => {
+            let deadline = clock() + IO_RETRY_DELAY_MS;
+            while clock() < deadline {
+                core::hint::spin_loop();
+            }
+        }
+        None => {
+            for _ in 0..IO_RETRY_FALLBACK_SPINS {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoFailure {
+    /// Worth trying again
+    Transient,
+    /// Retrying won't help
+    Permanent,
+}
+
+/// Sorts the errors returned by `IdeHandle::read` and `IdeHandle::write`.
+/// Aborted commands and timeouts are transient, everything else is permanent.
+pub fn ide_failure(err: IdeError) -> IoFailure {
+    match err {
+        IdeError::Aborted | IdeError::Timeout => IoFailure::Transient,
+        _ => IoFailure::Permanent,
+    }
+}
+
+/// Sorts the errors returned by `ahci_read` and `ahci_write`.
+/// A busy port, a timeout or an aborted command is transient, media errors are permanent.
+pub fn ahci_failure(err: AhciError) -> IoFailure {
+    // ATA error register: UNC (uncorrectable data), IDNF (sector not found), BBK (bad block)
+    const ATA_ER_UNRECOVERABLE: u8 = 0x40 | 0x10 | 0x80;
+
+    match err {
+        AhciError::Busy | AhciError::Timeout => IoFailure::Transient,
+        AhciError::TaskFileError(error) if error & ATA_ER_UNRECOVERABLE != 0 => IoFailure::Permanent,
+        AhciError::TaskFileError(_) => IoFailure::Transient,
+        AhciError::InvalidBuffer => IoFailure::Permanent,
+    }
+}
+
+/// Sorts a block layer error by the driver error behind it.
+/// Errors that didn't come from the drive are permanent.
+pub fn block_failure(err: &BlockError) -> IoFailure {
+    match err {
+        BlockError::DeviceError(DeviceError::Ide(err)) => ide_failure(*err),
+        BlockError::DeviceError(DeviceError::Ahci(err)) => ahci_failure(*err),
+        _ => IoFailure::Permanent,
+    }
+}
+
+/// Runs `op` up to `attempts` times, stopping at the first success or at an error `classify` calls permanent.
+/// The last error is returned unchanged.
+pub fn retry_io<T, E>(
+    attempts: u32,
+    classify: impl Fn(&E) -> IoFailure,
+    mut op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts && classify(&err) == IoFailure::Transient => {
+                println!("I/O error, retrying ({}/{})", attempt, attempts - 1);
+                attempt += 1;
+                retry_delay();
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
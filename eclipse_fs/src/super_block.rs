@@ -1,6 +1,8 @@
 use core::fmt;
-use ide::IDE_DEVICES;
+use ide::ide_get_drive;
 use eclipse_framebuffer::println;
+use crate::checksum::crc32;
+use crate::inodes::Inode;
 
 /// Superblock structure
 pub struct SuperBlock {
@@ -11,6 +13,8 @@ pub struct SuperBlock {
     pub blocks: u64,
     inodes: u16,
     reserved: u16,
+    /// Inode of `/`, 0 on filesystems formatted before the root directory existed
+    pub root_inode: u16,
     
     pub superblock_blocks: u64,
     pub inode_table_start: u64,
@@ -24,15 +28,22 @@ pub struct SuperBlock {
 
 impl SuperBlock {
     const MAGIC: u16 = 0xEC1;
-    const VERSION: u8 = 1;
+    /// Version 2 added permissions to the inode, version 3 timestamps, version 4 the checksum
+    const VERSION: u8 = 4;
+    /// First version that stores `CHECKSUM_OFFSET`, older ones have zeros there
+    const CHECKSUM_VERSION: u8 = 4;
     const DEFAULT_INODES: u16 = 500;
     const RESERVED: u16 = 500;
-    const INODE_SIZE: u64 = 128;
+    /// Directory entries use inode 0 to mark a free slot, so the root starts at 1
+    pub const ROOT_INODE: u16 = 1;
+    const INODE_SIZE: u64 = Inode::V3_SIZE as u64;
     const SECTOR_SIZE: u64 = 512;
     const SUPERBLOCK_SIZE: usize = 512;
+    /// The CRC32 covers every byte before it
+    const CHECKSUM_OFFSET: usize = 104;
 
     pub fn new(drive: u8) -> Self {
-        let sector_count = unsafe { IDE_DEVICES[drive as usize].size };
+        let sector_count = ide_get_drive(drive as usize).map_or(0, |disk| disk.size_sectors());
         let size_bytes = sector_count * Self::SECTOR_SIZE;
         
         if size_bytes == 0 {
@@ -59,6 +70,7 @@ impl SuperBlock {
             blocks,
             inodes: Self::DEFAULT_INODES,
             reserved: Self::RESERVED,
+            root_inode: Self::ROOT_INODE,
             superblock_blocks,
             inode_table_start,
             inode_table_blocks,
@@ -70,6 +82,15 @@ impl SuperBlock {
         }
     }
     
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Bytes each inode takes in the inode table, older versions use shorter layouts.
+    pub fn inode_size(&self) -> u64 {
+        Inode::disk_size(self.version) as u64
+    }
+    
     fn calculate_block_size(size_bytes: u64) -> u64 {
         const KB: u64 = 1024;
         
@@ -104,6 +125,7 @@ impl SuperBlock {
         bytes[24..32].copy_from_slice(&self.blocks.to_le_bytes());
         bytes[32..34].copy_from_slice(&self.inodes.to_le_bytes());
         bytes[34..36].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes[36..38].copy_from_slice(&self.root_inode.to_le_bytes());
         bytes[40..48].copy_from_slice(&self.superblock_blocks.to_le_bytes());
         bytes[48..56].copy_from_slice(&self.inode_table_start.to_le_bytes());
         bytes[56..64].copy_from_slice(&self.inode_table_blocks.to_le_bytes());
@@ -113,11 +135,14 @@ impl SuperBlock {
         bytes[88..96].copy_from_slice(&self.reserved_start.to_le_bytes());
         bytes[96..104].copy_from_slice(&self.reserved_blocks.to_le_bytes());
         
+        let checksum = crc32(&bytes[..Self::CHECKSUM_OFFSET]);
+        bytes[Self::CHECKSUM_OFFSET..Self::CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        
         bytes
     }
     
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
-        if bytes.len() < 104 { 
+        if bytes.len() < Self::CHECKSUM_OFFSET + 4 { 
             return Err("Buffer too small");
         }
         
@@ -126,6 +151,11 @@ impl SuperBlock {
             return Err("Invalid magic number");
         }
         
+        let stored = u32::from_le_bytes(bytes[Self::CHECKSUM_OFFSET..Self::CHECKSUM_OFFSET + 4].try_into().unwrap());
+        if bytes[2] >= Self::CHECKSUM_VERSION && stored != crc32(&bytes[..Self::CHECKSUM_OFFSET]) {
+            return Err("Superblock checksum mismatch");
+        }
+        
         Ok(Self {
             magic,
             version: bytes[2],
@@ -134,6 +164,7 @@ impl SuperBlock {
             blocks: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
             inodes: u16::from_le_bytes([bytes[32], bytes[33]]),
             reserved: u16::from_le_bytes([bytes[34], bytes[35]]),
+            root_inode: u16::from_le_bytes([bytes[36], bytes[37]]),
             superblock_blocks: u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
             inode_table_start: u64::from_le_bytes(bytes[48..56].try_into().unwrap()),
             inode_table_blocks: u64::from_le_bytes(bytes[56..64].try_into().unwrap()),
@@ -146,15 +177,16 @@ impl SuperBlock {
     }
 
     pub fn read_super_block(drive: u8) -> Result<Self, &'static str> {
-        use ide::ide_read_sectors;
         use alloc::vec::Vec;
 
         // Read sector 1
         let mut buffer: Vec<u8> = alloc::vec![0u8; 512];
 
-        let status = ide_read_sectors(drive as usize, 1, &mut buffer);
-
-        if status != 0 {
+        let disk = ide_get_drive(drive as usize).ok_or("No such drive")?;
+        if crate::journal::journal_replay(drive as usize).is_err() {
+            return Err("Failed to replay journal");
+        }
+        if disk.read(1, &mut buffer).is_err() {
             return Err("Failed to read superblock from disk");
         }
 
@@ -176,8 +208,8 @@ impl fmt::Display for SuperBlock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "SuperBlock {{ magic: 0x{:X}, version: {}, size: {} bytes, block_size: {}, blocks: {}, inodes: {}, inode_table: blocks {}-{}, block_bitmap: blocks {}-{}, reserved: blocks {}-{}, data_start: block {} }}",
-            self.magic, self.version, self.size, self.block_size, self.blocks, self.inodes,
+            "SuperBlock {{ magic: 0x{:X}, version: {}, size: {} bytes, block_size: {}, blocks: {}, inodes: {}, root: inode {}, inode_table: blocks {}-{}, block_bitmap: blocks {}-{}, reserved: blocks {}-{}, data_start: block {} }}",
+            self.magic, self.version, self.size, self.block_size, self.blocks, self.inodes, self.root_inode,
             self.inode_table_start, self.inode_table_start + self.inode_table_blocks - 1,
             self.block_bitmap_start, self.block_bitmap_start + self.block_bitmap_blocks - 1,
             self.reserved_start, self.reserved_start + self.reserved_blocks - 1,
@@ -5,6 +5,9 @@
 //! It was also known as IDE
 extern crate alloc;
 
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
 use eclipse_framebuffer::println;
 use bare_x86_64::*;
 
@@ -42,6 +45,9 @@ const ATA_CMD_IDENTIFY: u8 = 0xEC;
 
 const ATAPI_CMD_READ: u8 = 0xA8;
 const ATAPI_CMD_EJECT: u8 = 0x1B;
+const ATAPI_CMD_READ_10: u8 = 0x28;
+
+const ATAPI_SECTOR_SIZE: usize = 2048;
 
 const ATA_IDENT_DEVICETYPE: usize = 0;
 const ATA_IDENT_CYLINDERS: usize = 2;
@@ -86,6 +92,27 @@ const ATA_READ: u8 = 0x00;
 const ATA_WRITE: u8 = 0x01;
 
 const MAX_SECTORS_PER_TRANSFER: usize = 128;
+const MAX_IDE_DEVICES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdeError {
+    /// No drive in that slot
+    NotPresent,
+    /// The buffer isn't a whole number of sectors
+    InvalidBuffer,
+    DeviceFault,
+    NoAddressMark,
+    NoMedia,
+    Aborted,
+    IdNotFound,
+    Uncorrectable,
+    BadSector,
+    /// The drive never became ready
+    Timeout,
+    WriteProtected,
+    /// The command doesn't apply to this kind of drive, e.g. an ATA read on a CD-ROM
+    WrongDeviceType,
+}
 
 #[repr(C)]
 struct IDEChannelRegisters {
@@ -101,7 +128,16 @@ static mut CHANNELS: [IDEChannelRegisters; 2] = [
 ];
 
 static mut IDE_BUF: [u8; 512] = [0; 512];
-static mut IDE_IRQ_INVOKED: u8 = 0;
+static IDE_IRQ_INVOKED: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait for a drive before giving up
+const DEFAULT_IDE_TIMEOUT_MS: u64 = 1000;
+/// Used instead of the clock until `ide_set_clock` has been called
+const IDE_FALLBACK_SPINS: usize = 1_000_000;
+
+static IDE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_IDE_TIMEOUT_MS);
+/// Tick counter and milliseconds per tick, provided by the kernel
+static mut IDE_CLOCK: Option<(fn() -> u64, u64)> = None;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -117,9 +153,17 @@ pub struct IdeDevice {
     pub model: [u8; 41],
 }
 
+impl IdeDevice {
+    /// The drive's model name with the space and null padding trimmed.
+    pub fn model_str(&self) -> &str {
+        let end = self.model.iter().position(|&b| b == 0).unwrap_or(self.model.len());
+        core::str::from_utf8(&self.model[..end]).unwrap_or("Unknown").trim_end()
+    }
+}
+
 pub static mut COUNT: usize = 0;
 
-pub static mut IDE_DEVICES: [IdeDevice; 4] = [
+pub static mut IDE_DEVICES: [IdeDevice; MAX_IDE_DEVICES] = [
     IdeDevice { reserved: 0, channel: 0, drive: 0, device_type: 0, signature: 0, capabilities: 0, command_sets: 0, size: 0, model: [0; 41] },
     IdeDevice { reserved: 0, channel: 0, drive: 0, device_type: 0, signature: 0, capabilities: 0, command_sets: 0, size: 0, model: [0; 41] },
     IdeDevice { reserved: 0, channel: 0, drive: 0, device_type: 0, signature: 0, capabilities: 0, command_sets: 0, size: 0, model: [0; 41] },
@@ -175,9 +219,7 @@ fn ide_read_buffer(channel: u8, reg: u8, buffer: *mut u32, quads: u32) {
             0x0E..=0x15 => CHANNELS[channel as usize].bmide + (reg - 0x0E) as u16,
             _ => return,
         };
-        for i in 0..quads {
-            *buffer.add(i as usize) = inl!(port);
-        }
+        insl!(port, buffer, quads as usize);
         if reg > 0x07 && reg < 0x0C {
             outb!(CHANNELS[channel as usize].ctrl, CHANNELS[channel as usize].nien);
         }
@@ -196,9 +238,7 @@ fn ide_write_buffer(channel: u8, reg: u8, buffer: *const u32, quads: u32) {
             0x0E..=0x15 => CHANNELS[channel as usize].bmide + (reg - 0x0E) as u16,
             _ => return,
         };
-        for i in 0..quads {
-            outl!(port, *buffer.add(i as usize));
-        }
+        outsl!(port, buffer, quads as usize);
         if reg > 0x07 && reg < 0x0C {
             outb!(CHANNELS[channel as usize].ctrl, CHANNELS[channel as usize].nien);
         }
@@ -210,16 +250,8 @@ fn ide_polling(channel: u8, advanced_check: bool) -> u8 {
         let _ = ide_read(channel, ATA_REG_ALTSTATUS);
     }
 
-    let mut timeout = 100000;
-    loop {
-        let status = ide_read(channel, ATA_REG_STATUS);
-        if (status & ATA_SR_BSY) == 0 {
-            break;
-        }
-        timeout -= 1;
-        if timeout == 0 {
-            return 3;
-        }
+    if !ide_wait_not_busy(channel) {
+        return 3;
     }
 
     if advanced_check {
@@ -238,77 +270,113 @@ fn ide_polling(channel: u8, advanced_check: bool) -> u8 {
     0
 }
 
-fn ide_wait_irq(channel: u8) -> u8 {
+/// Gives the driver a tick counter so its timeouts are in wall clock time.
+pub fn ide_set_clock(get_ticks: fn() -> u64, ms_per_tick: u64) {
     unsafe {
-        let mut timeout: usize = 1_000_000;
-        while IDE_IRQ_INVOKED == 0 && timeout > 0 {
-            timeout -= 1;
-        }
-        IDE_IRQ_INVOKED = 0;
-
-        let status = ide_read(channel, ATA_REG_STATUS);
-        if (status & ATA_SR_ERR) != 0 { return 2; }
-        if (status & ATA_SR_DF) != 0 { return 1; }
-        if (status & ATA_SR_DRQ) == 0 { return 3; }
-        0
+        IDE_CLOCK = Some((get_ticks, ms_per_tick.max(1)));
     }
 }
 
-pub fn ide_irq_handler() {
-    unsafe {
-        let _ = ide_read(ATA_PRIMARY, ATA_REG_STATUS);
-        let _ = ide_read(ATA_SECONDARY, ATA_REG_STATUS);
-        IDE_IRQ_INVOKED = 1;
-    }
+/// Sets how long a single wait on the drive may take before it counts as a timeout.
+pub fn ide_set_timeout_ms(ms: u64) {
+    IDE_TIMEOUT_MS.store(ms, Ordering::Relaxed);
 }
 
-fn ide_print_error(drive: usize, mut err: u8) -> u8 {
-    if err == 0 {
-        return err;
+/// Spins until `done` returns true, returns false if the timeout ran out first.
+fn ide_wait_until(mut done: impl FnMut() -> bool) -> bool {
+    match unsafe { IDE_CLOCK } {
+        Some((get_ticks, ms_per_tick)) => {
+            // Round up and add a tick since we may start right before the next one
+            let timeout_ticks = IDE_TIMEOUT_MS.load(Ordering::Relaxed).div_ceil(ms_per_tick) + 1;
+            let start = get_ticks();
+            while !done() {
+                if get_ticks().wrapping_sub(start) >= timeout_ticks {
+                    return false;
+                }
+                core::hint::spin_loop();
+            }
+            true
+        }
+        None => {
+            for _ in 0..IDE_FALLBACK_SPINS {
+                if done() {
+                    return true;
+                }
+                core::hint::spin_loop();
+            }
+            false
+        }
     }
+}
 
+fn ide_wait_not_busy(channel: u8) -> bool {
+    ide_wait_until(|| (ide_read(channel, ATA_REG_STATUS) & ATA_SR_BSY) == 0)
+}
+
+fn ide_wait_irq(channel: u8) -> u8 {
+    // A missed IRQ isn't fatal, the status check below still sees the data if it's there
+    let _ = ide_wait_until(|| IDE_IRQ_INVOKED.load(Ordering::Acquire));
+    IDE_IRQ_INVOKED.store(false, Ordering::Release);
+
+    let status = ide_read(channel, ATA_REG_STATUS);
+    if (status & ATA_SR_ERR) != 0 { return 2; }
+    if (status & ATA_SR_DF) != 0 { return 1; }
+    if (status & ATA_SR_DRQ) == 0 { return 3; }
+    0
+}
+
+pub fn ide_irq_handler() {
+    let _ = ide_read(ATA_PRIMARY, ATA_REG_STATUS);
+    let _ = ide_read(ATA_SECONDARY, ATA_REG_STATUS);
+    IDE_IRQ_INVOKED.store(true, Ordering::Release);
+}
+
+/// Prints what went wrong and turns a polling status (1 = fault, 2 = error, 3 = not ready, 4 = write protected) into an `IdeError`.
+fn ide_print_error(drive: usize, err: u8) -> IdeError {
     println!("IDE:");
+    let mut error = IdeError::DeviceFault;
     if err == 1 {
         println!("- Device Fault");
-        err = 19;
     } else if err == 2 {
         let st = ide_read(unsafe { IDE_DEVICES }[drive].channel, ATA_REG_ERROR);
-        if st & ATA_ER_AMNF != 0 { println!("- No Address Mark Found"); err = 7; }
-        if st & ATA_ER_TK0NF != 0 { println!("- No Media or Media Error"); err = 3; }
-        if st & ATA_ER_ABRT != 0 { println!("- Command Aborted"); err = 20; }
-        if st & ATA_ER_MCR != 0 { println!("- No Media or Media Error"); err = 3; }
-        if st & ATA_ER_IDNF != 0 { println!("- ID mark not Found"); err = 21; }
-        if st & ATA_ER_MC != 0 { println!("- No Media or Media Error"); err = 3; }
-        if st & ATA_ER_UNC != 0 { println!("- Uncorrectable Data Error"); err = 22; }
-        if st & ATA_ER_BBK != 0 { println!("- Bad Sectors"); err = 13; }
+        if st & ATA_ER_AMNF != 0 { println!("- No Address Mark Found"); error = IdeError::NoAddressMark; }
+        if st & ATA_ER_TK0NF != 0 { println!("- No Media or Media Error"); error = IdeError::NoMedia; }
+        if st & ATA_ER_ABRT != 0 { println!("- Command Aborted"); error = IdeError::Aborted; }
+        if st & ATA_ER_MCR != 0 { println!("- No Media or Media Error"); error = IdeError::NoMedia; }
+        if st & ATA_ER_IDNF != 0 { println!("- ID mark not Found"); error = IdeError::IdNotFound; }
+        if st & ATA_ER_MC != 0 { println!("- No Media or Media Error"); error = IdeError::NoMedia; }
+        if st & ATA_ER_UNC != 0 { println!("- Uncorrectable Data Error"); error = IdeError::Uncorrectable; }
+        if st & ATA_ER_BBK != 0 { println!("- Bad Sectors"); error = IdeError::BadSector; }
     } else if err == 3 {
         println!("- Reads Nothing");
-        err = 23;
+        error = IdeError::Timeout;
     } else if err == 4 {
         println!("- Write Protected");
-        err = 8;
+        error = IdeError::WriteProtected;
     }
 
     println!(
         "- [{} {}] {}",
         ["Primary", "Secondary"][unsafe { IDE_DEVICES }[drive].channel as usize],
         ["Master", "Slave"][unsafe { IDE_DEVICES }[drive].drive as usize],
-        core::str::from_utf8(&unsafe { IDE_DEVICES }[drive].model).unwrap_or("Unknown")
+        unsafe { IDE_DEVICES }[drive].model_str()
     );
 
-    err
+    error
 }
 
-pub fn ide_read_sectors(drive: usize, lba: u64, buffer: &mut [u8]) -> u8 {
+fn ide_read_sectors(drive: usize, lba: u64, buffer: &mut [u8]) -> Result<(), IdeError> {
     unsafe {
+        if drive >= MAX_IDE_DEVICES { return Err(IdeError::NotPresent); }
         let dev = &IDE_DEVICES[drive];
-        if dev.reserved == 0 { return 1; }
+        if dev.reserved == 0 { return Err(IdeError::NotPresent); }
+        if dev.device_type != IDE_ATA as u16 { return Err(IdeError::WrongDeviceType); }
         let channel = dev.channel;
         let drive_bit = dev.drive;
         let total_sectors = buffer.len() / 512;
         
-        if buffer.len() < total_sectors * 512 {
-            return 1;
+        if buffer.len() != total_sectors * 512 {
+            return Err(IdeError::InvalidBuffer);
         }
         
         let mut sectors_read = 0;
@@ -317,7 +385,9 @@ pub fn ide_read_sectors(drive: usize, lba: u64, buffer: &mut [u8]) -> u8 {
             let current_lba = lba + sectors_read as u64;
             let use_lba48 = current_lba >= 0x10000000 || sectors_to_read > 256;
             
-            while (ide_read(channel, ATA_REG_STATUS) & ATA_SR_BSY) != 0 {}
+            if !ide_wait_not_busy(channel) {
+                return Err(ide_print_error(drive, 3));
+            }
             
             if use_lba48 {
                 ide_write(channel, ATA_REG_HDDEVSEL, 0x40 | ((drive_bit as u8) << 4));
@@ -342,21 +412,23 @@ pub fn ide_read_sectors(drive: usize, lba: u64, buffer: &mut [u8]) -> u8 {
             
             for s in 0..sectors_to_read {
                 let err = ide_wait_irq(channel);
-                if err != 0 { return ide_print_error(drive, err); }
+                if err != 0 { return Err(ide_print_error(drive, err)); }
                 let offset = (sectors_read + s) * 512;
                 ide_read_buffer(channel, ATA_REG_DATA, 
                     buffer.as_mut_ptr().add(offset).cast::<u32>(), 128);
             }
             sectors_read += sectors_to_read;
         }
-        0
+        Ok(())
     }
 }
 
-pub fn ide_write_sectors(drive: usize, lba: u64, data: &[u8]) -> u8 {
+fn ide_write_sectors(drive: usize, lba: u64, data: &[u8]) -> Result<(), IdeError> {
     unsafe {
+        if drive >= MAX_IDE_DEVICES { return Err(IdeError::NotPresent); }
         let dev = &IDE_DEVICES[drive];
-        if dev.reserved == 0 { return 1; }
+        if dev.reserved == 0 { return Err(IdeError::NotPresent); }
+        if dev.device_type != IDE_ATA as u16 { return Err(IdeError::WrongDeviceType); }
         let channel = dev.channel;
         let drive_bit = dev.drive;
         let data_size = data.len();
@@ -369,7 +441,9 @@ pub fn ide_write_sectors(drive: usize, lba: u64, data: &[u8]) -> u8 {
             let current_lba = lba + sectors_written as u64;
             let use_lba48 = current_lba >= 0x10000000 || sectors_to_write > 256;
             
-            while (ide_read(channel, ATA_REG_STATUS) & ATA_SR_BSY) != 0 {}
+            if !ide_wait_not_busy(channel) {
+                return Err(ide_print_error(drive, 3));
+            }
             
             if use_lba48 {
                 ide_write(channel, ATA_REG_HDDEVSEL, 0x40 | ((drive_bit as u8) << 4));
@@ -394,7 +468,7 @@ pub fn ide_write_sectors(drive: usize, lba: u64, data: &[u8]) -> u8 {
             
             for s in 0..sectors_to_write {
                 let err = ide_polling(channel, true);
-                if err != 0 { return ide_print_error(drive, err); }
+                if err != 0 { return Err(ide_print_error(drive, err)); }
                 
                 let offset = (sectors_written + s) * 512;
                 let bytes_left = data_size.saturating_sub(offset);
@@ -412,13 +486,116 @@ pub fn ide_write_sectors(drive: usize, lba: u64, data: &[u8]) -> u8 {
                 
                 ide_write(channel, ATA_REG_COMMAND, ATA_CMD_CACHE_FLUSH);
                 let flush_err = ide_polling(channel, false);
-                if flush_err != 0 { return ide_print_error(drive, flush_err); }
+                if flush_err != 0 { return Err(ide_print_error(drive, flush_err)); }
             }
             
             sectors_written += sectors_to_write;
         }
         
-        0
+        Ok(())
+    }
+}
+
+/// A drive slot that `ide_init` found a device in, get one with `ide_get_drive`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdeHandle(usize);
+
+/// Returns a handle for drive `index` (0-3), `None` if the index is out of range or the slot is empty.
+pub fn ide_get_drive(index: usize) -> Option<IdeHandle> {
+    if index >= MAX_IDE_DEVICES || unsafe { IDE_DEVICES[index].reserved } == 0 {
+        return None;
+    }
+    Some(IdeHandle(index))
+}
+
+impl IdeHandle {
+    /// Index into `IDE_DEVICES`
+    pub fn index(&self) -> usize {
+        self.0
+    }
+
+    /// Reads `buffer.len() / 512` sectors starting at `lba`, the buffer must be a whole number of sectors.
+    pub fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IdeError> {
+        ide_read_sectors(self.0, lba, buffer)
+    }
+
+    /// Writes `data` starting at `lba`, a partial last sector is padded with zeros.
+    pub fn write(&self, lba: u64, data: &[u8]) -> Result<(), IdeError> {
+        ide_write_sectors(self.0, lba, data)
+    }
+
+    /// Reads `sectors` 2048-byte sectors starting at `lba` from an ATAPI drive,
+    /// `buffer` is resized to hold exactly the data read.
+    pub fn atapi_read(&self, lba: u32, sectors: u16, buffer: &mut Vec<u8>) -> Result<(), IdeError> {
+        atapi_read_sectors(self.0, lba, sectors, buffer)
+    }
+
+    pub fn size_sectors(&self) -> u64 {
+        unsafe { IDE_DEVICES[self.0].size }
+    }
+}
+
+/// Reads `sectors` 2048-byte sectors from an ATAPI drive starting at `lba` using a SCSI READ(10) packet.
+/// `buffer` is resized to hold exactly the data read.
+fn atapi_read_sectors(drive: usize, lba: u32, sectors: u16, buffer: &mut Vec<u8>) -> Result<(), IdeError> {
+    unsafe {
+        if drive >= MAX_IDE_DEVICES { return Err(IdeError::NotPresent); }
+        let dev = &IDE_DEVICES[drive];
+        if dev.reserved == 0 { return Err(IdeError::NotPresent); }
+        if dev.device_type != IDE_ATAPI as u16 { return Err(IdeError::WrongDeviceType); }
+        let channel = dev.channel;
+        let drive_bit = dev.drive;
+
+        buffer.clear();
+        buffer.resize(sectors as usize * ATAPI_SECTOR_SIZE, 0);
+        if sectors == 0 {
+            return Ok(());
+        }
+
+        let packet: [u8; 12] = [
+            ATAPI_CMD_READ_10,
+            0,
+            ((lba >> 24) & 0xFF) as u8,
+            ((lba >> 16) & 0xFF) as u8,
+            ((lba >> 8) & 0xFF) as u8,
+            (lba & 0xFF) as u8,
+            0,
+            ((sectors >> 8) & 0xFF) as u8,
+            (sectors & 0xFF) as u8,
+            0,
+            0,
+            0,
+        ];
+
+        ide_write(channel, ATA_REG_HDDEVSEL, 0xA0 | (drive_bit << 4));
+        for _ in 0..4 { let _ = ide_read(channel, ATA_REG_ALTSTATUS); }
+
+        // PIO mode, the byte count registers hold the size of one DRQ block
+        ide_write(channel, ATA_REG_FEATURES, 0);
+        ide_write(channel, ATA_REG_LBA1, (ATAPI_SECTOR_SIZE & 0xFF) as u8);
+        ide_write(channel, ATA_REG_LBA2, (ATAPI_SECTOR_SIZE >> 8) as u8);
+        ide_write(channel, ATA_REG_COMMAND, ATA_CMD_PACKET);
+
+        let err = ide_polling(channel, true);
+        if err != 0 { return Err(ide_print_error(drive, err)); }
+
+        let data_port = CHANNELS[channel as usize].base;
+        for word in packet.chunks(2) {
+            outw!(data_port, u16::from_le_bytes([word[0], word[1]]));
+        }
+
+        for s in 0..sectors as usize {
+            let err = ide_wait_irq(channel);
+            if err != 0 { return Err(ide_print_error(drive, err)); }
+            ide_read_buffer(channel, ATA_REG_DATA,
+                buffer.as_mut_ptr().add(s * ATAPI_SECTOR_SIZE).cast::<u32>(), (ATAPI_SECTOR_SIZE / 4) as u32);
+        }
+
+        if !ide_wait_until(|| (ide_read(channel, ATA_REG_STATUS) & (ATA_SR_BSY | ATA_SR_DRQ)) == 0) {
+            return Err(IdeError::Timeout);
+        }
+
+        Ok(())
     }
 }
 
@@ -457,23 +634,43 @@ pub fn ide_init(bar0: u8, bar1: u8, bar2: u8, bar3: u8, bar4: u8) {
 
                 ide_write(j as u8, ATA_REG_COMMAND, ATA_CMD_IDENTIFY);
                 
+                let mut device_type = IDE_ATA;
                 let mut timeout = 100000;
                 loop {
                     let status = ide_read(j as u8, ATA_REG_STATUS);
                     if status == 0 { break; }
-                    if (status & ATA_SR_ERR) != 0 { break; }
+                    if (status & ATA_SR_ERR) != 0 {
+                        // Packet devices abort IDENTIFY and leave their signature in LBA1/LBA2
+                        let cl = ide_read(j as u8, ATA_REG_LBA1);
+                        let ch = ide_read(j as u8, ATA_REG_LBA2);
+                        if device_type == IDE_ATA && ((cl == 0x14 && ch == 0xEB) || (cl == 0x69 && ch == 0x96)) {
+                            device_type = IDE_ATAPI;
+                            ide_write(j as u8, ATA_REG_COMMAND, ATA_CMD_IDENTIFY_PACKET);
+                            timeout = 100000;
+                            continue;
+                        }
+                        break;
+                    }
                     if (status & ATA_SR_BSY) == 0 && (status & ATA_SR_DRQ) != 0 {
                         let mut buf = IDE_BUF;
                         ide_read_buffer(j as u8, ATA_REG_DATA, buf.as_mut_ptr().cast::<u32>(), 128);
 
+                        // The model string is stored as big endian 16-bit words
                         let model_offset = ATA_IDENT_MODEL;
-                        for m in 0..40 {
-                            IDE_DEVICES[drive_index].model[m] = buf[model_offset + m];
+                        for m in (0..40).step_by(2) {
+                            IDE_DEVICES[drive_index].model[m] = buf[model_offset + m + 1];
+                            IDE_DEVICES[drive_index].model[m + 1] = buf[model_offset + m];
                         }
 
                         IDE_DEVICES[drive_index].model[40] = 0;
                         IDE_DEVICES[drive_index].reserved = 1;
-                        IDE_DEVICES[drive_index].device_type = IDE_ATA as u16;
+                        IDE_DEVICES[drive_index].device_type = device_type as u16;
+
+                        if device_type == IDE_ATAPI {
+                            println!("Device {}: ATAPI", drive_index);
+                            COUNT += 1;
+                            break;
+                        }
 
                         let commands_sets = u16::from_le_bytes([
                             buf[ATA_IDENT_COMMANDSETS],
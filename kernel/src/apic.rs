@@ -0,0 +1,138 @@
+//! Local APIC
+//! Takes the timer tick over from the PIT when the CPU has an APIC. Device interrupts stay
+//! on the 8259 PICs until there is an I/O APIC driver to route them.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use bare_x86_64::cpu::cpu_types::CPUFunctions;
+use bare_x86_64::cpu::msr::{read_msr, write_msr};
+use eclipse_framebuffer::println;
+
+use crate::idt::unregister_irq;
+use crate::mem::mem::{VMM, VirtAddr, PhysAddr, PageTableEntry, HHDM_OFFSET};
+use crate::time;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Local APIC registers, offsets from the MMIO base
+const APIC_REG_ID: usize = 0x20;
+const APIC_REG_TPR: usize = 0x80;
+const APIC_REG_EOI: usize = 0xB0;
+const APIC_REG_SVR: usize = 0xF0;
+const APIC_REG_LVT_TIMER: usize = 0x320;
+const APIC_REG_TIMER_INITIAL: usize = 0x380;
+const APIC_REG_TIMER_CURRENT: usize = 0x390;
+const APIC_REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const APIC_SVR_ENABLE: u32 = 1 << 8;
+const APIC_LVT_MASKED: u32 = 1 << 16;
+const APIC_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16
+const APIC_TIMER_DIVIDE_16: u32 = 0x3;
+
+pub const APIC_TIMER_VECTOR: u8 = 48;
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// PIT ticks the APIC timer is measured against
+const CALIBRATION_PIT_TICKS: u64 = 10;
+
+/// Virtual address of the local APIC registers, 0 while the PIC is in charge
+static APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+fn read_reg(reg: usize) -> u32 {
+    let base = APIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+
+fn write_reg(reg: usize, value: u32) {
+    let base = APIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, value) }
+}
+
+/// Whether CPUID reports a local APIC.
+pub fn is_supported() -> bool {
+    CPUFunctions::new().has_apic
+}
+
+/// Whether `apic_init` switched the tick source over to the APIC timer.
+pub fn is_enabled() -> bool {
+    APIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Local APIC ID of the running CPU.
+pub fn id() -> u8 {
+    (read_reg(APIC_REG_ID) >> 24) as u8
+}
+
+/// Signals end of interrupt for a vector delivered by the local APIC.
+pub fn eoi() {
+    if is_enabled() {
+        write_reg(APIC_REG_EOI, 0);
+    }
+}
+
+/// Enables the local APIC and moves the timer tick from the PIT to the APIC timer,
+/// firing at the same rate so `time` stays correct. Needs interrupts on, the PIT is used
+/// to calibrate. Returns false and leaves the PIT running if there is no APIC.
+///
+/// # Safety
+/// Must run once on the boot CPU after the VMM and IDT are set up, with the APIC vectors
+/// installed. It maps the APIC registers and reprograms the interrupt source of the tick.
+pub unsafe fn apic_init() -> bool {
+    if !is_supported() {
+        println!("No local APIC, staying on the PIC");
+        return false;
+    }
+
+    let base_msr = read_msr(IA32_APIC_BASE_MSR);
+    let phys = base_msr & APIC_BASE_ADDR_MASK;
+    let virt = phys | HHDM_OFFSET;
+
+    if VMM::map_page(
+        VirtAddr::new(virt),
+        PhysAddr::new(phys),
+        PageTableEntry::PRESENT | PageTableEntry::WRITABLE | PageTableEntry::NO_CACHE,
+    ).is_none() {
+        println!("Failed to map the local APIC");
+        return false;
+    }
+
+    write_msr(IA32_APIC_BASE_MSR, base_msr | APIC_BASE_ENABLE);
+    APIC_BASE.store(virt, Ordering::Relaxed);
+
+    write_reg(APIC_REG_TPR, 0);
+    write_reg(APIC_REG_SVR, APIC_SVR_ENABLE | APIC_SPURIOUS_VECTOR as u32);
+
+    // Count APIC timer ticks across a few PIT ticks
+    write_reg(APIC_REG_TIMER_DIVIDE, APIC_TIMER_DIVIDE_16);
+    write_reg(APIC_REG_LVT_TIMER, APIC_LVT_MASKED);
+
+    let start = time::get_ticks();
+    while time::get_ticks() == start {
+        core::hint::spin_loop();
+    }
+    write_reg(APIC_REG_TIMER_INITIAL, u32::MAX);
+    let begin = time::get_ticks();
+    while time::get_ticks() - begin < CALIBRATION_PIT_TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read_reg(APIC_REG_TIMER_CURRENT);
+    write_reg(APIC_REG_TIMER_INITIAL, 0);
+
+    let per_tick = elapsed / CALIBRATION_PIT_TICKS as u32;
+    if per_tick == 0 {
+        println!("APIC timer did not count, staying on the PIT");
+        APIC_BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+
+    // Hand the tick over: mask the PIT line on the PIC, start the APIC timer at the same rate.
+    // The APIC timer handler acknowledges through `eoi`, device lines keep the PIC EOI.
+    unregister_irq(0);
+    write_reg(APIC_REG_LVT_TIMER, APIC_TIMER_PERIODIC | APIC_TIMER_VECTOR as u32);
+    write_reg(APIC_REG_TIMER_INITIAL, per_tick);
+
+    println!("Local APIC {} enabled, timer at {} Hz ({} counts per tick)", id(), time::pit_frequency(), per_tick);
+    true
+}
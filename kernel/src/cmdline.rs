@@ -0,0 +1,68 @@
+//! Kernel command line parsing
+//! Limine hands over the `cmdline:` string from `limine.conf`, it is split on whitespace
+//! into `key=value` options and bare flags, e.g. `cmdline: fsdrive=1 noselftest`
+
+use core::ptr::addr_of;
+use limine::response::ExecutableCmdlineResponse;
+
+const MAX_CMDLINE_ARGS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct CmdlineArg {
+    key: &'static str,
+    value: Option<&'static str>,
+}
+
+static mut CMDLINE: &str = "";
+static mut CMDLINE_ARGS: [CmdlineArg; MAX_CMDLINE_ARGS] = [CmdlineArg { key: "", value: None }; MAX_CMDLINE_ARGS];
+static mut CMDLINE_ARG_COUNT: usize = 0;
+
+/// Parses the command line Limine passed to the kernel.
+/// Anything past `MAX_CMDLINE_ARGS` options is ignored.
+///
+/// # Safety
+/// Must run once on the boot CPU before anything reads the command line, it writes the
+/// parsed options without synchronization.
+pub unsafe fn init(response: &'static ExecutableCmdlineResponse) {
+    let cmdline = response.cmdline().to_str().unwrap_or("");
+    CMDLINE = cmdline;
+    CMDLINE_ARG_COUNT = 0;
+
+    for arg in cmdline.split_ascii_whitespace() {
+        if CMDLINE_ARG_COUNT >= MAX_CMDLINE_ARGS {
+            break;
+        }
+
+        let parsed = match arg.split_once('=') {
+            Some((key, value)) => CmdlineArg { key, value: Some(value) },
+            None => CmdlineArg { key: arg, value: None },
+        };
+
+        CMDLINE_ARGS[CMDLINE_ARG_COUNT] = parsed;
+        CMDLINE_ARG_COUNT += 1;
+    }
+}
+
+fn args() -> &'static [CmdlineArg] {
+    let args = unsafe { &*addr_of!(CMDLINE_ARGS) };
+    &args[..unsafe { CMDLINE_ARG_COUNT }]
+}
+
+/// The full, unparsed command line.
+pub fn cmdline_raw() -> &'static str {
+    unsafe { CMDLINE }
+}
+
+/// Returns the value of a `key=value` option, the last one wins if a key is repeated.
+pub fn cmdline_get(key: &str) -> Option<&'static str> {
+    args().iter().rev().find(|arg| arg.key == key).and_then(|arg| arg.value)
+}
+
+/// Returns true for a bare `key` flag or a `key=1`/`key=true`/`key=yes`/`key=on` option.
+pub fn cmdline_flag(key: &str) -> bool {
+    match args().iter().rev().find(|arg| arg.key == key) {
+        Some(CmdlineArg { value: None, .. }) => true,
+        Some(CmdlineArg { value: Some(value), .. }) => matches!(*value, "1" | "true" | "yes" | "on"),
+        None => false,
+    }
+}
@@ -78,6 +78,21 @@ struct KernelStack([u8; 4096]);
 
 static mut KERNEL_STACK: KernelStack = KernelStack([0; 4096]);
 
+/// IST slots as the IDT numbers them (0 means IST1)
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+const FAULT_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(align(16))]
+struct FaultStack([u8; FAULT_STACK_SIZE]);
+
+// A kernel stack overflow shows up as a page fault on the guard page below the stack.
+// Handling it on that same stack would fault again and escalate to a double fault, so both
+// handlers switch to stacks of their own and can still print what went wrong.
+static mut DOUBLE_FAULT_STACK: FaultStack = FaultStack([0; FAULT_STACK_SIZE]);
+static mut PAGE_FAULT_STACK: FaultStack = FaultStack([0; FAULT_STACK_SIZE]);
+
 unsafe fn gdt_set_entry(
     index: usize,
     base: u32,
@@ -147,6 +162,9 @@ pub unsafe fn gdt_init() {
         }
         
         KERNEL_TSS.rsp0 = addr_of!(KERNEL_STACK.0) as u64 + 4096;
+        // Stacks grow down, the IST entries point at their top
+        KERNEL_TSS.ist1 = addr_of!(DOUBLE_FAULT_STACK.0) as u64 + FAULT_STACK_SIZE as u64;
+        KERNEL_TSS.ist2 = addr_of!(PAGE_FAULT_STACK.0) as u64 + FAULT_STACK_SIZE as u64;
         KERNEL_TSS.iopb_offset = core::mem::size_of::<Tss>() as u16;
         
         gdt_set_tss(
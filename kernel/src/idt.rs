@@ -2,8 +2,8 @@
 
 use core::ptr::addr_of_mut;
 use spin::Mutex;
-use eclipse_framebuffer::print;
 use pic8259::ChainedPics;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 use ide::ide_irq_handler;
@@ -15,6 +15,81 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Legacy IRQ lines behind the two PICs
+pub const IRQ_COUNT: usize = 16;
+/// The secondary PIC is chained to this line of the primary one
+const IRQ_CASCADE: u8 = 2;
+
+/// Callbacks run by the IRQ stubs, the stub sends EOI after the callback returns
+static mut IRQ_HANDLERS: [Option<fn()>; IRQ_COUNT] = [None; IRQ_COUNT];
+
+/// Installs `handler` for legacy IRQ `irq` and unmasks the line.
+/// Replaces any earlier handler. The handler runs in interrupt context.
+pub fn register_irq(irq: u8, handler: fn()) {
+    if irq as usize >= IRQ_COUNT {
+        return;
+    }
+
+    without_interrupts(|| unsafe {
+        IRQ_HANDLERS[irq as usize] = Some(handler);
+
+        let mut pics = PICS.lock();
+        let mut masks = pics.read_masks();
+        if irq < 8 {
+            masks[0] &= !(1 << irq);
+        } else {
+            masks[0] &= !(1 << IRQ_CASCADE);
+            masks[1] &= !(1 << (irq - 8));
+        }
+        pics.write_masks(masks[0], masks[1]);
+    });
+}
+
+/// Removes the handler for `irq` and masks the line again.
+pub fn unregister_irq(irq: u8) {
+    if irq as usize >= IRQ_COUNT || irq == IRQ_CASCADE {
+        return;
+    }
+
+    without_interrupts(|| unsafe {
+        IRQ_HANDLERS[irq as usize] = None;
+
+        let mut pics = PICS.lock();
+        let mut masks = pics.read_masks();
+        if irq < 8 {
+            masks[0] |= 1 << irq;
+        } else {
+            masks[1] |= 1 << (irq - 8);
+        }
+        pics.write_masks(masks[0], masks[1]);
+    });
+}
+
+fn dispatch_irq(irq: u8) {
+    if let Some(handler) = unsafe { IRQ_HANDLERS[irq as usize] } {
+        handler();
+    }
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq); }
+}
+
+/// The x86-interrupt ABI doesn't pass the vector, so every line gets its own stub
+macro_rules! irq_stubs {
+    ($($name:ident = $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 = 0, irq1 = 1, irq2 = 2, irq3 = 3, irq4 = 4, irq5 = 5, irq6 = 6, irq7 = 7,
+    irq8 = 8, irq9 = 9, irq10 = 10, irq11 = 11, irq12 = 12, irq13 = 13, irq14 = 14, irq15 = 15,
+}
+
 pub unsafe fn idt_init() {
     let idt = &mut *addr_of_mut!(IDT);
     
@@ -26,12 +101,16 @@ pub unsafe fn idt_init() {
     idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
     idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
     idt.device_not_available.set_handler_fn(device_not_available_handler);
-    idt.double_fault.set_handler_fn(double_fault_handler);
+    idt.double_fault
+        .set_handler_fn(double_fault_handler)
+        .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
     idt.invalid_tss.set_handler_fn(invalid_tss_handler);
     idt.segment_not_present.set_handler_fn(segment_not_present_handler);
     idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
     idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.page_fault
+        .set_handler_fn(page_fault_handler)
+        .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
     idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
     idt.alignment_check.set_handler_fn(alignment_check_handler);
     idt.machine_check.set_handler_fn(machine_check_handler);
@@ -41,19 +120,21 @@ pub unsafe fn idt_init() {
 
     let mut pics = PICS.lock();
     pics.initialize();
-
-    let mut masks = pics.read_masks();
-    masks[0] &= !(1 << 0);
-    masks[1] &= !(1 << 6);
-    masks[1] &= !(1 << 7);
-    pics.write_masks(masks[0], masks[1]);
+    // Everything starts masked, `register_irq` opens lines as handlers show up
+    pics.write_masks(0xFF, 0xFF);
     drop(pics);
 
-    idt[PIC_1_OFFSET].set_handler_fn(timer_handler);
-    idt[PIC_2_OFFSET + 6].set_handler_fn(ide_primary_handler);
-    idt[PIC_2_OFFSET + 7].set_handler_fn(ide_secondary_handler);
+    for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[PIC_1_OFFSET + irq as u8].set_handler_fn(*stub);
+    }
+    idt[crate::apic::APIC_TIMER_VECTOR].set_handler_fn(apic_timer_handler);
+    idt[crate::apic::APIC_SPURIOUS_VECTOR].set_handler_fn(apic_spurious_handler);
 
     idt.load();
+
+    register_irq(0, crate::time::tick);
+    register_irq(14, ide_irq_handler);
+    register_irq(15, ide_irq_handler);
 }
 
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
@@ -163,18 +244,10 @@ extern "x86-interrupt" fn security_exception_handler(
     panic!("EXCEPTION: SECURITY EXCEPTION\nError Code: {}\n{:#?}", error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    
-    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET); }
-}
-
-extern "x86-interrupt" fn ide_primary_handler(_stack_frame: InterruptStackFrame) {
-    ide_irq_handler();
-    unsafe { PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 6); }
+extern "x86-interrupt" fn apic_timer_handler(_stack_frame: InterruptStackFrame) {
+    crate::time::tick();
+    crate::apic::eoi();
 }
 
-extern "x86-interrupt" fn ide_secondary_handler(_stack_frame: InterruptStackFrame) {
-    ide_irq_handler();
-    unsafe { PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 7); }
-}
\ No newline at end of file
+/// Spurious APIC interrupts must not be acknowledged
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
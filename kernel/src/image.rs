@@ -0,0 +1,16 @@
+//! Images stored on the filesystem
+//! Looks a BMP file up in a directory and draws it through the framebuffer renderer
+
+use eclipse_framebuffer::ScrollingTextRenderer;
+use eclipse_fs::directory::DirectoryManager;
+use eclipse_fs::file_ops::read_file;
+use eclipse_fs::InodeManager;
+
+/// Draws the BMP called `name` in directory `dir` with its top left corner at `(x, y)`.
+pub fn show_bmp(inode_manager: &InodeManager, dir: u16, name: &[u8], x: usize, y: usize) -> Result<(), &'static str> {
+    let inode = DirectoryManager::find_entry(inode_manager, dir, name)
+        .map_err(|_| "Failed to search directory")?
+        .ok_or("Image not found")?;
+    let data = read_file(inode_manager, inode).map_err(|_| "Failed to read image")?;
+    ScrollingTextRenderer::get().blit_bmp(x, y, &data)
+}
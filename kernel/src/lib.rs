@@ -5,9 +5,17 @@
 extern crate alloc;
 
 // Modules
+pub mod apic;
+pub mod cmdline;
 pub mod gdt;
 pub mod idt;
+pub mod image;
 pub mod mem;
+pub mod net;
+pub mod rtc;
+pub mod selftest;
+pub mod serial;
+pub mod time;
 
 // C functions go here
 unsafe extern "C" {
@@ -8,20 +8,16 @@ use core::arch::asm;
 // External crates
 
 use limine::BaseRevision;
-use limine::request::{FramebufferRequest, MemoryMapRequest, RequestsEndMarker, RequestsStartMarker};
+use limine::request::{ExecutableCmdlineRequest, FramebufferRequest, MemoryMapRequest, RequestsEndMarker, RequestsStartMarker};
 
 // Eclipse crates
-use eclipse_framebuffer::{ ScrollingTextRenderer, println, print, panic_print};
-use ide::ide_init;
-use eclipse_fs::{SuperBlock, write_eclipse_fs};
-use eclipse_fs::file_ops::{create_file, read_file, delete_file};
-use eclipse_fs::directory::DirectoryManager;
-use eclipse_fs::inodes::InodeManager;
-use ahci::find_ahci_controller;
-use eclipse_pci::{check_all_buses, pci_find_ahci_controller, pci_enable_bus_master, pci_enable_memory_space};
+use eclipse_framebuffer::{ ChannelLayout, ScrollingTextRenderer, println, panic_print};
+use ide::{ide_init, ide_set_clock};
+use ahci::{ahci_init_from_abar, ahci_set_clock, AHCI_DMA_SIZE};
+use pci::{check_all_buses, pci_decode_bar, pci_find_ahci_controller, pci_enable_bus_master, pci_enable_memory_space};
 use eclipse_threader::scheduler::scheduler::scheduler_init;
 use eclipse_os::mem::mem::{VMM, VirtAddr, PhysAddr, PageTableEntry};
-use eclipse_os::{gdt, idt, mem::mem};
+use eclipse_os::{apic, cmdline, gdt, idt, mem::mem, net, rtc, selftest, serial, time};
 
 static FONT: &[u8] = include_bytes!("../../eclipse_framebuffer/font/altc-8x16.psf");
 
@@ -37,6 +33,10 @@ static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 #[unsafe(link_section = ".requests")]
 static MEMMAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
 static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
@@ -46,6 +46,7 @@ static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn kmain() -> ! {
+    serial::serial_init();
     assert!(BASE_REVISION.is_supported());
     let framebuffer_response = FRAMEBUFFER_REQUEST.get_response().expect("No framebuffer");
     let framebuffer = framebuffer_response.framebuffers().next().expect("No framebuffer available");
@@ -57,11 +58,31 @@ unsafe extern "C" fn kmain() -> ! {
         framebuffer.bpp() as usize,
         FONT,
     );
+    ScrollingTextRenderer::get().set_channel_layout(ChannelLayout {
+        red: (framebuffer.red_mask_shift(), framebuffer.red_mask_size()),
+        green: (framebuffer.green_mask_shift(), framebuffer.green_mask_size()),
+        blue: (framebuffer.blue_mask_shift(), framebuffer.blue_mask_size()),
+    });
+    if let Some(cmdline_response) = EXECUTABLE_CMDLINE_REQUEST.get_response() {
+        cmdline::init(cmdline_response);
+        println!("Command line: {}", cmdline::cmdline_raw());
+    }
+
     println!("Initializing Memory Allocator...");
     if let Some(memmap_response) = MEMMAP_REQUEST.get_response() {
         mem::VMM::init(memmap_response);
-        mem::init_allocator(memmap_response);
+        let (heap_base, heap_size) = mem::init_allocator(memmap_response);
         println!("Memory Allocator Initialized");
+        println!("Heap: base 0x{:X}, size {} KiB", heap_base, heap_size / 1024);
+
+        // Draw into RAM from here on so scrolling doesn't tear
+        // Allocated as u32 so 32 bpp pixel stores are aligned
+        let backbuffer_len = framebuffer.pitch() as usize * framebuffer.height() as usize;
+        let pixels = alloc::vec![0u32; backbuffer_len.div_ceil(4)].leak();
+        let backbuffer = core::slice::from_raw_parts_mut(pixels.as_mut_ptr() as *mut u8, backbuffer_len);
+        if let Err(e) = ScrollingTextRenderer::get().enable_backbuffer(backbuffer) {
+            println!("Backbuffer disabled: {}", e);
+        }
     } else {
         println!("WARNING: No memory map available!");
     }
@@ -72,10 +93,20 @@ unsafe extern "C" fn kmain() -> ! {
     println!("Initializing IDT...");
     idt::idt_init();
     println!("IDT Initialized");
+    let pit_hz = time::set_pit_frequency(time::DEFAULT_PIT_HZ);
+    println!("PIT running at {} Hz", pit_hz);
+    let boot_time = rtc::get_current_time();
+    println!("RTC time: {}", boot_time);
+    time::set_boot_time(boot_time.to_unix_seconds());
+    eclipse_fs::set_clock(time::unix_time);
+    eclipse_fs::retry::set_retry_clock(time::get_time_ms);
     asm!("sti");
 
     println!("Interrupts enabled");
+    println!("TSC running at {} MHz", time::calibrate_tsc() / 1_000_000);
+    serial::init_interrupt();
     println!("Initializing IDE");
+    ide_set_clock(time::get_time_ms, 1);
     ide_init(0, 0, 0, 0, 0);
     println!("IDE Initialized");
     
@@ -84,9 +115,11 @@ unsafe extern "C" fn kmain() -> ! {
     println!("PCI scan complete");
 
     println!("Initializing AHCI");
+    ahci_set_clock(time::get_time_ms);
     match pci_find_ahci_controller() {
         Some(ahci_dev) => {
-            let abar_phys = ahci_dev.bar[5] as u64 & !0xF;
+            let abar_phys = pci_decode_bar(ahci_dev.bus, ahci_dev.device, ahci_dev.function, 5)
+                .map_or(0, |bar| bar.base);
             println!("AHCI controller found at {}:{}:{}", ahci_dev.bus, ahci_dev.device, ahci_dev.function);
             println!("AHCI BAR5 (physical): 0x{:X}", abar_phys);
 
@@ -124,7 +157,20 @@ unsafe extern "C" fn kmain() -> ! {
 
                 println!("AHCI ABAR mapped successfully");
 
-                find_ahci_controller();
+                let dma_pages = AHCI_DMA_SIZE.div_ceil(4096);
+                match mem::alloc_contiguous(dma_pages, 4096) {
+                    Some(dma) => {
+                        let dma_phys = dma.as_u64();
+                        match ahci_init_from_abar(abar_virt, dma_phys, dma_phys | mem::HHDM_OFFSET) {
+                            Some(_) => println!("AHCI Initialized"),
+                            None => {
+                                println!("AHCI initialization failed");
+                                mem::free_contiguous(dma, dma_pages);
+                            }
+                        }
+                    }
+                    None => println!("No memory for AHCI command lists"),
+                }
             }
         }
         None => {
@@ -132,111 +178,15 @@ unsafe extern "C" fn kmain() -> ! {
         }
     }
 
+    println!("Initializing Network");
+    net::net_init();
+
     println!("Mapping APIC...");
+    apic::apic_init();
 
-    
-    println!("Writing fs");
-    write_eclipse_fs(0);
-    
-    println!("Reading superblock from disk...");
-    let super_block: SuperBlock = match SuperBlock::read_super_block(0) {
-        Ok(sb) => {
-            println!("Superblock loaded: {}", sb);
-            sb
-        }
-        Err(e) => {
-            println!("Failed to read superblock: {}", e);
-            hcf();
-        }
-    };
-    
-    println!("Loading bitmap from disk...");
-    let bitmap = match eclipse_fs::BlockBitmap::from_disk(0, &super_block) {
-        Ok(bm) => {
-            println!("Bitmap loaded successfully");
-            println!("Free blocks: {}", bm.free_blocks());
-            println!("Used blocks: {}", bm.used_blocks());
-            bm
-        }
-        Err(e) => {
-            println!("Failed to load bitmap: {:?}", e);
-            hcf();
-        }
-    };
-    
-    println!("\nInode Manager");
-    match InodeManager::new(0, super_block, bitmap) {
-        Ok(mut inode_manager) => {
-            println!("Inode Manager initialized");
-            
-            println!("\nTesting File Creation");
-            let test_data = b"Hello from EclipseOS!";
-            match create_file(&mut inode_manager, test_data) {
-                Ok(inode_idx) => {
-                    println!("File created at inode {}", inode_idx);
-                    
-                    println!("\nTesting File Reading");
-                    match read_file(&inode_manager, inode_idx) {
-                        Ok(file_data) => {
-                            println!("File read successfully: {} bytes", file_data.len());
-                            print!("File content: ");
-                            for &byte in file_data.iter() {
-                                print!("{}", byte as char);
-                            }
-                            println!();
-                        }
-                        Err(e) => println!("Failed to read file: {:?}", e),
-                    }
-                    
-                    println!("\nTesting Directory Creation");
-                    match DirectoryManager::create_directory(&mut inode_manager) {
-                        Ok(dir_inode) => {
-                            println!("Directory created at inode {}", dir_inode);
-                            
-                            println!("\nTesting Directory Entry Addition");
-                            match DirectoryManager::add_entry(&mut inode_manager, dir_inode, b"test_file.txt", inode_idx) {
-                                Ok(()) => {
-                                    println!("Entry added to directory");
-                                    
-                                    println!("\nTesting File Lookup");
-                                    match DirectoryManager::find_entry(&inode_manager, dir_inode, b"test_file.txt") {
-                                        Ok(Some(found_inode)) => {
-                                            println!("Found file at inode {}", found_inode);
-                                        }
-                                        Ok(None) => println!("File not found in directory"),
-                                        Err(e) => println!("Error searching directory: {:?}", e),
-                                    }
-                                    
-                                    println!("\nTesting Directory Listing");
-                                    match DirectoryManager::list_directory(&inode_manager, dir_inode) {
-                                        Ok(entries) => {
-                                            println!("Directory contains {} entries:", entries.len());
-                                            for (inode, name) in entries {
-                                                println!("  inode {}: {:?}", inode, core::str::from_utf8(&name).unwrap_or("invalid_utf8"));
-                                            }
-                                        }
-                                        Err(e) => println!("Error listing directory: {:?}", e),
-                                    }
-                                }
-                                Err(e) => println!("Failed to add entry: {:?}", e),
-                            }
-                        }
-                        Err(e) => println!("Failed to create directory: {:?}", e),
-                    }
-                    
-                    println!("\nTesting File Deletion");
-                    match delete_file(&mut inode_manager, inode_idx) {
-                        Ok(()) => println!("File deleted successfully"),
-                        Err(e) => println!("Failed to delete file: {:?}", e),
-                    }
-                }
-                Err(e) => println!("Failed to create file: {:?}", e),
-            }
-        }
-        Err(e) => println!("Failed to initialize inode manager: {:?}", e),
+    if !cmdline::cmdline_flag("noselftest") {
+        selftest::run_all();
     }
-    
-    println!("\nFilesystem Tests Complete");
 
     println!("Initializing Scheduler...");
     scheduler_init();
@@ -302,6 +252,12 @@ fn rust_panic(info: &core::panic::PanicInfo) -> ! {
         asm!("mov {:x}, cs", out(reg) cs);
         asm!("mov {:x}, ss", out(reg) ss);
     }
+
+    // Goes out first and without any lock so the message survives even if the framebuffer is what broke
+    {
+        use core::fmt::Write;
+        let _ = write!(serial::PanicSerialWriter, "KERNEL PANIC\n{}\n", info);
+    }
     
     panic_print!(
         "KERNEL PANIC\n{}\n\n\
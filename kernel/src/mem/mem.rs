@@ -1,5 +1,6 @@
 //! A FIFO (First In, First Out) allocator implemented using a linked list.
 //! Each block in the allocator is represented as a node in the list.
+//! Freeing the block at the top of the heap hands its space straight back to the bump pointer.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
@@ -8,8 +9,15 @@ use core::{
 };
 use limine::{memory_map::EntryType, response::MemoryMapResponse};
 
+/// Higher half direct map offset Limine maps all physical memory at
+pub const HHDM_OFFSET: u64 = 0xFFFF800000000000;
+
+/// Upper bound on the kernel heap carved out of the largest usable memory region
+pub const KERNEL_HEAP_SIZE: usize = 64 * 1024 * 1024;
+
 static mut HEAP_START: *mut u8 = null_mut();
 static mut HEAP_OFFSET: usize = 0;
+static mut HEAP_SIZE: usize = 0;
 
 /// Represents a block in the linked list allocator.
 struct LinkedListBlock {
@@ -97,13 +105,16 @@ pub struct LinkAllocator;
 
 unsafe impl GlobalAlloc for LinkAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let header = mem::size_of::<LinkedListBlock>();
+        let align = layout.align().max(mem::align_of::<LinkedListBlock>());
         let mut current = FREE_LIST.head;
         
         while !current.is_null() {
-            if (*current).size >= layout.size() {
+            let payload = (current as *mut u8).add(header);
+            if (*current).size >= layout.size() && (payload as usize).is_multiple_of(align) {
                 FREE_LIST.remove(current);
                 
-                return (current as *mut u8).add(mem::size_of::<LinkedListBlock>());
+                return payload;
             }
             current = (*current).next;
         }
@@ -112,19 +123,23 @@ unsafe impl GlobalAlloc for LinkAllocator {
             return null_mut();
         }
 
-        let total_size = mem::size_of::<LinkedListBlock>() + layout.size();
+        // Align the payload, the header sits right in front of it
+        let base = HEAP_START as usize;
+        let payload_offset = ((base + HEAP_OFFSET + header + align - 1) & !(align - 1)) - base;
+        let offset = payload_offset - header;
         
-        let align = layout.align().max(mem::align_of::<LinkedListBlock>());
-        let offset = (HEAP_OFFSET + align - 1) & !(align - 1);
+        if payload_offset + layout.size() > HEAP_SIZE {
+            return null_mut();
+        }
         
         let block = HEAP_START.add(offset) as *mut LinkedListBlock;
         (*block).size = layout.size();
         (*block).next = null_mut();
         (*block).prev = null_mut();
         
-        HEAP_OFFSET = offset + total_size;
+        HEAP_OFFSET = payload_offset + layout.size();
         
-        (block as *mut u8).add(mem::size_of::<LinkedListBlock>())
+        HEAP_START.add(payload_offset)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
@@ -134,6 +149,12 @@ unsafe impl GlobalAlloc for LinkAllocator {
 
         let block = ptr.sub(mem::size_of::<LinkedListBlock>()) as *mut LinkedListBlock;
         
+        // The most recent bump allocation can be undone instead of parked on the free list
+        if ptr.add((*block).size) == HEAP_START.add(HEAP_OFFSET) {
+            HEAP_OFFSET = block as usize - HEAP_START as usize;
+            return;
+        }
+        
         FREE_LIST.push_back(block);
     }
 }
@@ -141,21 +162,49 @@ unsafe impl GlobalAlloc for LinkAllocator {
 #[global_allocator]
 static ALLOCATOR: LinkAllocator = LinkAllocator;
 
-/// Initialize the allocator
-pub unsafe fn init_allocator(memory_map: &MemoryMapResponse) {
+/// Initialize the allocator on the largest usable region of the memory map.
+/// Must run after `VMM::init` so the heap frames can be taken away from the frame allocator.
+/// Returns the physical base and size of the heap.
+pub unsafe fn init_allocator(memory_map: &MemoryMapResponse) -> (u64, usize) {
     FREE_LIST = LinkedList::new();
     
+    let mut largest: Option<(u64, u64)> = None;
     for entry in memory_map.entries() {
-        if entry.entry_type == EntryType::USABLE && entry.length > 1024 * 1024 {
-            HEAP_START = (entry.base + 0xFFFF800000000000) as *mut u8;
-            HEAP_OFFSET = 0;
-            break;
+        if entry.entry_type == EntryType::USABLE && largest.is_none_or(|(_, length)| entry.length > length) {
+            largest = Some((entry.base, entry.length));
         }
     }
     
-    if HEAP_START.is_null() {
-        panic!("No usable memory found in memory map");
+    let (mut base, mut length) = match largest {
+        Some(region) => region,
+        None => panic!("No usable memory found in memory map"),
+    };
+    
+    // The frame bitmap sits at the start of a usable region, skip over it if it is this one
+    if !FRAME_BITMAP.is_null() && FRAME_BITMAP as u64 - HHDM_OFFSET == base {
+        let bitmap_bytes = ((BITMAP_SIZE * 8).div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64;
+        base += bitmap_bytes;
+        length = length.saturating_sub(bitmap_bytes);
+    }
+    
+    let size = (length.min(KERNEL_HEAP_SIZE as u64) as usize) & !(PAGE_SIZE - 1);
+    if size == 0 {
+        panic!("Largest usable memory region is too small for the heap");
     }
+    
+    HEAP_START = (base + HHDM_OFFSET) as *mut u8;
+    HEAP_OFFSET = 0;
+    HEAP_SIZE = size;
+    
+    // Stop the frame allocator from handing out pages that now belong to the heap
+    if !FRAME_BITMAP.is_null() {
+        let first_frame = base as usize / PAGE_SIZE;
+        for frame in first_frame..first_frame + size / PAGE_SIZE {
+            FrameAllocator::mark_used(frame);
+        }
+    }
+    
+    (base, size)
 }
 
 const PAGE_SIZE: usize = 4096;
@@ -287,7 +336,7 @@ impl FrameAllocator {
         
         for entry in memory_map.entries() {
             if entry.entry_type == EntryType::USABLE && entry.length >= (BITMAP_SIZE * 8) as u64 {
-                FRAME_BITMAP = (entry.base + 0xFFFF800000000000) as *mut u64;
+                FRAME_BITMAP = (entry.base + HHDM_OFFSET) as *mut u64;
                 
                 for i in 0..BITMAP_SIZE {
                     *FRAME_BITMAP.add(i) = 0xFFFFFFFFFFFFFFFF;
@@ -353,6 +402,53 @@ impl FrameAllocator {
         let frame = (addr.as_u64() / PAGE_SIZE as u64) as usize;
         Self::mark_free(frame);
     }
+    
+    unsafe fn is_free(frame: usize) -> bool {
+        frame < TOTAL_FRAMES && (*FRAME_BITMAP.add(frame / 64) & (1u64 << (frame % 64))) != 0
+    }
+}
+
+/// Allocates `num_pages` physically contiguous frames starting on an `align` byte boundary,
+/// for DMA buffers that devices address directly. `align` is rounded up to a whole page.
+///
+/// # Safety
+/// The frame allocator must be initialized and not used concurrently.
+pub unsafe fn alloc_contiguous(num_pages: usize, align: usize) -> Option<PhysAddr> {
+    if num_pages == 0 || FRAME_BITMAP.is_null() {
+        return None;
+    }
+
+    let align_frames = align.div_ceil(PAGE_SIZE).max(1);
+    let mut start = 0;
+
+    while start + num_pages <= TOTAL_FRAMES {
+        match (start..start + num_pages).find(|&frame| !FrameAllocator::is_free(frame)) {
+            Some(used) => {
+                // Restart on the next aligned frame past the one in the way
+                start = (used + 1).div_ceil(align_frames) * align_frames;
+            }
+            None => {
+                for frame in start..start + num_pages {
+                    FrameAllocator::mark_used(frame);
+                }
+                return Some(PhysAddr::new((start * PAGE_SIZE) as u64));
+            }
+        }
+    }
+
+    None
+}
+
+/// Gives back a range handed out by `alloc_contiguous`.
+///
+/// # Safety
+/// `addr` and `num_pages` must come from one `alloc_contiguous` call, and nothing, including
+/// a device doing DMA, may access the frames afterwards.
+pub unsafe fn free_contiguous(addr: PhysAddr, num_pages: usize) {
+    let first = (addr.as_u64() / PAGE_SIZE as u64) as usize;
+    for frame in first..first + num_pages {
+        FrameAllocator::mark_free(frame);
+    }
 }
 
 static mut KERNEL_PAGE_TABLE: *mut PageTable = null_mut();
@@ -381,8 +477,6 @@ impl VMM {
     }
     
     pub unsafe fn map_page(virt: VirtAddr, phys: PhysAddr, flags: u64) -> Option<()> {
-        const HHDM_OFFSET: u64 = 0xFFFF800000000000;
-
         let p4 = &mut *(((KERNEL_PAGE_TABLE as u64) | HHDM_OFFSET) as *mut PageTable);
 
         let p3_entry = &mut p4.entries[virt.p4_index()];
@@ -425,27 +519,68 @@ impl VMM {
         Some(())
     }
     
-    pub unsafe fn unmap_page(virt: VirtAddr) {
-        let p4 = &mut *KERNEL_PAGE_TABLE;
-        
-        if !p4.entries[virt.p4_index()].is_present() {
-            return;
-        }
-        
-        let p3 = p4.entries[virt.p4_index()].get_addr().as_u64() as *mut PageTable;
-        if !(*p3).entries[virt.p3_index()].is_present() {
-            return;
+    /// Walks the page tables down to the 4 KiB entry mapping `virt`.
+    /// `None` if a level is missing or the address is covered by a huge page.
+    unsafe fn leaf_entry(virt: VirtAddr) -> Option<*mut PageTableEntry> {
+        let mut table = ((KERNEL_PAGE_TABLE as u64) | HHDM_OFFSET) as *mut PageTable;
+
+        for index in [virt.p4_index(), virt.p3_index(), virt.p2_index()] {
+            let entry = &(*table).entries[index];
+            if !entry.is_present() || entry.0 & PageTableEntry::HUGE != 0 {
+                return None;
+            }
+            table = (entry.get_addr().as_u64() | HHDM_OFFSET) as *mut PageTable;
         }
-        
-        let p2 = (*p3).entries[virt.p3_index()].get_addr().as_u64() as *mut PageTable;
-        if !(*p2).entries[virt.p2_index()].is_present() {
-            return;
+
+        Some(&mut (*table).entries[virt.p1_index()] as *mut PageTableEntry)
+    }
+
+    /// Removes the 4 KiB mapping at `virt` and returns the frame it pointed to.
+    /// The frame itself is not freed. Huge page mappings are left alone.
+    pub unsafe fn unmap_page(virt: VirtAddr) -> Option<PhysAddr> {
+        let entry = Self::leaf_entry(virt)?;
+        if !(*entry).is_present() {
+            return None;
         }
-        
-        let p1 = (*p2).entries[virt.p2_index()].get_addr().as_u64() as *mut PageTable;
-        (*p1).entries[virt.p1_index()].clear();
-        
+
+        let phys = (*entry).get_addr();
+        (*entry).clear();
+
         core::arch::asm!("invlpg [{}]", in(reg) virt.as_u64(), options(nostack, preserves_flags));
+
+        Some(phys)
+    }
+
+    /// Physical address `virt` maps to, including the offset into the page.
+    /// Follows 1 GiB and 2 MiB pages as well.
+    ///
+    /// # Safety
+    /// `VMM::init` must have run, the page tables are read through the direct map.
+    pub unsafe fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+        let mut table = ((KERNEL_PAGE_TABLE as u64) | HHDM_OFFSET) as *mut PageTable;
+        let levels = [
+            (virt.p4_index(), 0),
+            (virt.p3_index(), 1u64 << 30),
+            (virt.p2_index(), 1u64 << 21),
+            (virt.p1_index(), 1u64 << 12),
+        ];
+
+        for (index, page_size) in levels {
+            let entry = (*table).entries[index];
+            if !entry.is_present() {
+                return None;
+            }
+
+            let is_leaf = page_size == 1 << 12 || (page_size != 0 && entry.0 & PageTableEntry::HUGE != 0);
+            if is_leaf {
+                let base = entry.0 & 0x000F_FFFF_FFFF_F000 & !(page_size - 1);
+                return Some(PhysAddr(base | (virt.as_u64() & (page_size - 1))));
+            }
+
+            table = (entry.get_addr().as_u64() | HHDM_OFFSET) as *mut PageTable;
+        }
+
+        None
     }
     
     pub unsafe fn enable_paging() {
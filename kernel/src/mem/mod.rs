@@ -1 +1 @@
-pub mod mem;
\ No newline at end of file
+pub mod mem;
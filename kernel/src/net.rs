@@ -0,0 +1,94 @@
+//! Network controller detection
+//! Only finds the NIC and reads its MAC address for now, no packets are sent or received
+
+use pci::{pci_decode_bar, pci_enable_bus_master, pci_enable_memory_space, pci_find_device};
+use eclipse_framebuffer::println;
+
+use crate::mem::mem::{VMM, VirtAddr, PhysAddr, PageTableEntry, HHDM_OFFSET};
+
+// Intel 82540EM (e1000), the default NIC in QEMU
+const E1000_VENDOR_ID: u16 = 0x8086;
+const E1000_DEVICE_ID: u16 = 0x100E;
+
+// e1000 registers
+const E1000_REG_RAL0: usize = 0x5400;
+const E1000_REG_RAH0: usize = 0x5404;
+const E1000_RAH_AV: u32 = 1 << 31;
+
+/// The register window is 128 KiB
+const E1000_MMIO_PAGES: u64 = 32;
+
+static mut NET_MAC: Option<[u8; 6]> = None;
+
+/// Looks for a supported NIC, maps its registers and reads the MAC address.
+///
+/// # Safety
+/// Needs the VMM and PCI scan set up, and must run once on the boot CPU: it maps the
+/// register window and writes the stored MAC without synchronization.
+pub unsafe fn net_init() {
+    let Some(nic) = pci_find_device(E1000_VENDOR_ID, E1000_DEVICE_ID) else {
+        println!("No network controller found");
+        return;
+    };
+
+    println!("e1000 found at {}:{}:{}", nic.bus, nic.device, nic.function);
+
+    let Some(bar0) = pci_decode_bar(nic.bus, nic.device, nic.function, 0) else {
+        println!("e1000 BAR0 is not implemented");
+        return;
+    };
+    if bar0.is_io {
+        println!("e1000 BAR0 is not memory mapped");
+        return;
+    }
+
+    let mmio_phys = bar0.base;
+    if mmio_phys == 0 {
+        println!("Invalid e1000 BAR address");
+        return;
+    }
+
+    pci_enable_bus_master(nic.bus, nic.device, nic.function);
+    pci_enable_memory_space(nic.bus, nic.device, nic.function);
+
+    let mmio_virt = mmio_phys | HHDM_OFFSET;
+    for i in 0..E1000_MMIO_PAGES {
+        let offset = i * 0x1000;
+        if VMM::map_page(
+            VirtAddr::new(mmio_virt + offset),
+            PhysAddr::new(mmio_phys + offset),
+            PageTableEntry::PRESENT | PageTableEntry::WRITABLE | PageTableEntry::NO_CACHE,
+        ).is_none() {
+            println!("Failed to map e1000 page at offset 0x{:X}", offset);
+            return;
+        }
+    }
+
+    let ral = core::ptr::read_volatile((mmio_virt as usize + E1000_REG_RAL0) as *const u32);
+    let rah = core::ptr::read_volatile((mmio_virt as usize + E1000_REG_RAH0) as *const u32);
+
+    if rah & E1000_RAH_AV == 0 {
+        println!("e1000 has no MAC address loaded");
+        return;
+    }
+
+    let mac = [
+        ral as u8,
+        (ral >> 8) as u8,
+        (ral >> 16) as u8,
+        (ral >> 24) as u8,
+        rah as u8,
+        (rah >> 8) as u8,
+    ];
+    NET_MAC = Some(mac);
+
+    println!(
+        "MAC: {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    );
+}
+
+/// MAC address of the detected NIC, `None` if there is none.
+pub fn net_mac() -> Option<[u8; 6]> {
+    unsafe { NET_MAC }
+}
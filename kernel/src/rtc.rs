@@ -0,0 +1,204 @@
+//! CMOS real time clock
+//! Reads the wall clock time, handling the update-in-progress window and BCD/12-hour encodings,
+//! and can run the periodic interrupt on IRQ 8 as a second time source next to the PIT
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use bare_x86_64::{inb, outb};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::idt::register_irq;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+
+const RTC_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RTC_B_24_HOUR: u8 = 1 << 1;
+const RTC_B_BINARY: u8 = 1 << 2;
+const RTC_B_PERIODIC_INT: u8 = 1 << 6;
+const RTC_HOUR_PM: u8 = 1 << 7;
+
+/// The CMOS year register only holds two digits
+const RTC_CENTURY: u16 = 2000;
+
+/// Periodic rate `r` fires at `32768 >> (r - 1)` Hz, rates below 3 are unusable
+const RTC_MIN_RATE: u8 = 3;
+const RTC_MAX_RATE: u8 = 15;
+const RTC_BASE_HZ: u32 = 32768;
+const RTC_IRQ: u8 = 8;
+
+static RTC_TICKS: AtomicU64 = AtomicU64::new(0);
+static mut RTC_CALLBACK: Option<fn()> = None;
+
+/// Wall clock time as read from the RTC, always 24-hour
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, the RTC time is taken as UTC.
+    pub fn to_unix_seconds(&self) -> u64 {
+        // Days from the civil calendar, counting years from March so the leap day comes last
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn cmos_read(reg: u8) -> u8 {
+    outb!(CMOS_ADDRESS, reg);
+    inb!(CMOS_DATA)
+}
+
+fn cmos_write(reg: u8, value: u8) {
+    outb!(CMOS_ADDRESS, reg);
+    outb!(CMOS_DATA, value);
+}
+
+fn update_in_progress() -> bool {
+    cmos_read(RTC_STATUS_A) & RTC_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Raw register values: second, minute, hour, day, month, year.
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        cmos_read(RTC_SECONDS),
+        cmos_read(RTC_MINUTES),
+        cmos_read(RTC_HOURS),
+        cmos_read(RTC_DAY),
+        cmos_read(RTC_MONTH),
+        cmos_read(RTC_YEAR),
+    ]
+}
+
+/// Reads the current time. The registers are read until two passes agree,
+/// so an update landing halfway through a read is never returned.
+pub fn get_current_time() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = cmos_read(RTC_STATUS_B);
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year] = raw;
+
+    let pm = hour & RTC_HOUR_PM != 0;
+    hour &= !RTC_HOUR_PM;
+
+    if status_b & RTC_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+    }
+
+    // 12-hour mode counts 12, 1, ..., 11
+    if status_b & RTC_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: RTC_CENTURY + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Starts the periodic interrupt at the highest rate not above `rate_hz` (2 Hz to 8192 Hz)
+/// and unmasks IRQ 8. Returns the rate actually programmed.
+pub fn enable_periodic(rate_hz: u32) -> u32 {
+    let mut rate = RTC_MIN_RATE;
+    while rate < RTC_MAX_RATE && RTC_BASE_HZ >> (rate - 1) > rate_hz {
+        rate += 1;
+    }
+
+    without_interrupts(|| {
+        let status_a = cmos_read(RTC_STATUS_A);
+        cmos_write(RTC_STATUS_A, (status_a & 0xF0) | rate);
+        let status_b = cmos_read(RTC_STATUS_B);
+        cmos_write(RTC_STATUS_B, status_b | RTC_B_PERIODIC_INT);
+        // Drop anything already pending so the first interrupt gets through
+        cmos_read(RTC_STATUS_C);
+
+        register_irq(RTC_IRQ, rtc_irq_handler);
+    });
+
+    RTC_BASE_HZ >> (rate - 1)
+}
+
+/// Sets the function called on every periodic interrupt, it runs in interrupt context.
+pub fn set_periodic_callback(callback: fn()) {
+    without_interrupts(|| unsafe { RTC_CALLBACK = Some(callback) });
+}
+
+/// Number of periodic interrupts since `enable_periodic`.
+pub fn periodic_ticks() -> u64 {
+    RTC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the IRQ 8 handler. Reading register C acknowledges the interrupt,
+/// without it the RTC never fires again.
+pub fn rtc_irq_handler() {
+    cmos_read(RTC_STATUS_C);
+    RTC_TICKS.fetch_add(1, Ordering::Relaxed);
+
+    if let Some(callback) = unsafe { RTC_CALLBACK } {
+        callback();
+    }
+}
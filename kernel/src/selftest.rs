@@ -0,0 +1,500 @@
+//! Boot-time self checks
+//! Every check drives one subsystem through its basic success condition and reports
+//! `[OK]`/`[FAIL]` through `print_status`. A failing check never halts boot.
+
+use alloc::vec::Vec;
+use eclipse_framebuffer::println;
+use eclipse_fs::checksum::{adler32, crc32, crc32_update};
+use eclipse_fs::directory::DirectoryManager;
+use eclipse_fs::file_ops::{append, create_file, delete_file, read_file, truncate};
+use eclipse_fs::inodes::{Inode, InodeManager};
+use eclipse_fs::retry::{retry_io, IoFailure, IO_RETRY_DELAY_MS};
+use eclipse_fs::{write_eclipse_fs, BlockBitmap, SuperBlock};
+
+use crate::cmdline;
+use crate::mem::mem::{FrameAllocator, PageTableEntry, VirtAddr, VMM};
+use crate::time;
+
+const OK_COLOR: u32 = 0x00FF00;
+const FAIL_COLOR: u32 = 0xFF0000;
+const TEXT_COLOR: u32 = 0xFFFFFF;
+const BG_COLOR: u32 = 0x000000;
+
+/// How many `hlt` instructions the timer check waits for a tick before failing
+const TIMER_WAIT_HALTS: usize = 1000;
+/// Longest the filesystem check waits for the seconds clock to move past a file's mtime
+const MTIME_WAIT_MS: u64 = 2000;
+
+/// PSF2 header for 256 8x16 glyphs with no glyph data behind it.
+/// Aligned so the header can be read in place like a real font.
+#[repr(C, align(4))]
+struct TruncatedFont([u8; 32]);
+
+static TRUNCATED_PSF2: TruncatedFont = TruncatedFont([
+    0x72, 0xb5, 0x4a, 0x86, // magic
+    0, 0, 0, 0,             // version
+    32, 0, 0, 0,            // header size
+    0, 0, 0, 0,             // flags
+    0, 1, 0, 0,             // 256 glyphs
+    16, 0, 0, 0,            // bytes per glyph
+    16, 0, 0, 0,            // height
+    8, 0, 0, 0,             // width
+]);
+
+/// IDE drive the filesystem check formats unless `fsdrive=` picks another one
+const DEFAULT_FS_DRIVE: u8 = 0;
+
+/// First sector past the 28-bit LBA limit (2^28), only reachable with the 48-bit commands
+const LBA48_TEST_SECTOR: u64 = 0x1000_0000;
+
+/// Scratch address for the paging check, well above the direct map
+const PAGING_TEST_VIRT: u64 = 0xFFFF_C000_0000_0000;
+
+struct SelfTest {
+    name: &'static str,
+    run: fn() -> Result<(), &'static str>,
+}
+
+const TESTS: &[SelfTest] = &[
+    SelfTest { name: "Allocator", run: check_allocator },
+    SelfTest { name: "Paging", run: check_paging },
+    SelfTest { name: "Timer", run: check_timer },
+    SelfTest { name: "PCI", run: check_pci },
+    SelfTest { name: "Disk", run: check_disk },
+    SelfTest { name: "AHCI LBA48", run: check_ahci_lba48 },
+    SelfTest { name: "Font", run: check_font },
+    SelfTest { name: "Checksums", run: check_checksums },
+    SelfTest { name: "I/O retry", run: check_retry },
+    SelfTest { name: "Inode layouts", run: check_inode_layouts },
+    SelfTest { name: "Filesystem", run: check_filesystem },
+];
+
+/// Prints a single `[OK]`/`[FAIL]` line for a component.
+pub fn print_status(component: &str, result: Result<(), &str>) {
+    let renderer = eclipse_framebuffer::ScrollingTextRenderer::get();
+    match result {
+        Ok(()) => {
+            renderer.set_colors(OK_COLOR, BG_COLOR);
+            renderer.write_str("[OK]   ");
+            renderer.set_colors(TEXT_COLOR, BG_COLOR);
+            println!("{}", component);
+        }
+        Err(reason) => {
+            renderer.set_colors(FAIL_COLOR, BG_COLOR);
+            renderer.write_str("[FAIL] ");
+            renderer.set_colors(TEXT_COLOR, BG_COLOR);
+            println!("{}: {}", component, reason);
+        }
+    }
+}
+
+/// Runs every self check in order and returns `(passed, failed)`.
+pub fn run_all() -> (usize, usize) {
+    println!("\nRunning boot self checks...");
+
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for test in TESTS {
+        let result = (test.run)();
+        if result.is_ok() {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+        print_status(test.name, result);
+    }
+
+    println!("Self checks: {} passed, {} failed", passed, failed);
+    (passed, failed)
+}
+
+fn check_allocator() -> Result<(), &'static str> {
+    let mut values: Vec<u64> = Vec::with_capacity(64);
+    for i in 0..64 {
+        values.push(i * 3);
+    }
+
+    if values.iter().enumerate().any(|(i, &v)| v != i as u64 * 3) {
+        return Err("allocation contents corrupted");
+    }
+    drop(values);
+
+    // A second allocation after the free proves dealloc left the heap usable
+    let again: Vec<u8> = alloc::vec![0xAA; 256];
+    if again.iter().any(|&b| b != 0xAA) {
+        return Err("allocation after free corrupted");
+    }
+    drop(again);
+
+    // Every buffer is bigger than the last, so none fits a freed block. This adds up to
+    // well over the heap size and only passes if freed space goes back to the heap.
+    for i in 0..256 {
+        let buffer: Vec<u8> = alloc::vec![0x55; 64 * 1024 + i * 4096];
+        if buffer[buffer.len() - 1] != 0x55 {
+            return Err("allocation churn corrupted");
+        }
+    }
+
+    Ok(())
+}
+
+fn check_paging() -> Result<(), &'static str> {
+    let virt = VirtAddr::new(PAGING_TEST_VIRT);
+
+    unsafe {
+        let frame = FrameAllocator::alloc_frame().ok_or("no free frame")?;
+        let result = (|| {
+            VMM::map_page(virt, frame, PageTableEntry::WRITABLE).ok_or("map_page failed")?;
+            if VMM::translate(virt).map(|p| p.as_u64()) != Some(frame.as_u64()) {
+                return Err("translate does not match the mapping");
+            }
+            if VMM::unmap_page(virt).map(|p| p.as_u64()) != Some(frame.as_u64()) {
+                return Err("unmap_page returned the wrong frame");
+            }
+            if VMM::translate(virt).is_some() {
+                return Err("page still mapped after unmap_page");
+            }
+            Ok(())
+        })();
+        FrameAllocator::free_frame(frame);
+        result
+    }
+}
+
+fn check_timer() -> Result<(), &'static str> {
+    let start = time::get_ticks();
+
+    for _ in 0..TIMER_WAIT_HALTS {
+        if time::get_ticks() != start {
+            return Ok(());
+        }
+        unsafe { core::arch::asm!("hlt") };
+    }
+
+    Err("timer did not advance")
+}
+
+fn check_pci() -> Result<(), &'static str> {
+    if pci::pci_device_count() == 0 {
+        return Err("no PCI devices found");
+    }
+    Ok(())
+}
+
+fn check_disk() -> Result<(), &'static str> {
+    if unsafe { ide::COUNT } == 0 {
+        return Err("no disks detected");
+    }
+    Ok(())
+}
+
+fn check_font() -> Result<(), &'static str> {
+    let renderer = eclipse_framebuffer::ScrollingTextRenderer::get();
+    let font = renderer.font();
+    let size = renderer.char_size();
+
+    if renderer.set_font(&TRUNCATED_PSF2.0).is_ok() {
+        return Err("truncated PSF2 font was accepted");
+    }
+    if renderer.font().as_ptr() != font.as_ptr() || renderer.char_size() != size {
+        return Err("rejected font replaced the current one");
+    }
+    Ok(())
+}
+
+fn check_checksums() -> Result<(), &'static str> {
+    // Standard check values for CRC-32/ISO-HDLC and Adler-32
+    if crc32(b"123456789") != 0xCBF4_3926 {
+        return Err("crc32 check value mismatch");
+    }
+    if crc32_update(crc32_update(0, b"1234"), b"56789") != 0xCBF4_3926 {
+        return Err("incremental crc32 mismatch");
+    }
+    if adler32(b"Wikipedia") != 0x11E6_0398 {
+        return Err("adler32 check value mismatch");
+    }
+    Ok(())
+}
+
+fn check_retry() -> Result<(), &'static str> {
+    // Fails twice with a transient error, then succeeds
+    let mut attempts = 0;
+    let start = time::get_time_ms();
+    let result = retry_io(3, |_: &()| IoFailure::Transient, || {
+        attempts += 1;
+        if attempts < 3 { Err(()) } else { Ok(attempts) }
+    });
+    if result != Ok(3) || attempts != 3 {
+        return Err("transient errors were not retried");
+    }
+    if time::get_time_ms() - start < 2 * IO_RETRY_DELAY_MS {
+        return Err("retries did not back off");
+    }
+
+    // Permanent errors and the attempt limit both stop the loop
+    let mut attempts = 0;
+    let result: Result<(), ()> = retry_io(3, |_| IoFailure::Permanent, || {
+        attempts += 1;
+        Err(())
+    });
+    if result.is_ok() || attempts != 1 {
+        return Err("permanent error was retried");
+    }
+    let mut attempts = 0;
+    let result: Result<(), ()> = retry_io(2, |_| IoFailure::Transient, || {
+        attempts += 1;
+        Err(())
+    });
+    if result.is_ok() || attempts != 2 {
+        return Err("retry ran past the attempt limit");
+    }
+
+    Ok(())
+}
+
+fn check_inode_layouts() -> Result<(), &'static str> {
+    let mut inode = Inode::new();
+    inode.size = 0x1122_3344;
+    inode.direct_blocks[0] = 7;
+    inode.double_indirect_block = 0xDEAD;
+    inode.mode = 0o640;
+    inode.uid = 1000;
+    inode.gid = 100;
+    inode.mtime = 1_700_000_000;
+    inode.ctime = 1_600_000_000;
+
+    let bytes = inode.to_bytes();
+    if bytes.len() != Inode::V3_SIZE {
+        return Err("serialized inode has the wrong size");
+    }
+    let back = Inode::from_bytes(&bytes).map_err(|_| "failed to parse a version 3 inode")?;
+    if back.size != inode.size || back.direct_blocks != inode.direct_blocks
+        || back.double_indirect_block != inode.double_indirect_block || back.mode != inode.mode
+        || back.uid != inode.uid || back.gid != inode.gid
+        || back.mtime != inode.mtime || back.ctime != inode.ctime
+    {
+        return Err("inode round trip mismatch");
+    }
+
+    // Older layouts are a prefix of the newest, parsed at their own stride
+    let v2 = Inode::from_bytes(&bytes[..Inode::V2_SIZE]).map_err(|_| "failed to parse a version 2 inode")?;
+    if v2.double_indirect_block != 0xDEAD || v2.mode != 0o640 || v2.uid != 1000 || v2.mtime != 0 {
+        return Err("version 2 inode parsed wrong");
+    }
+    let v1 = Inode::from_bytes(&bytes[..Inode::V1_SIZE]).map_err(|_| "failed to parse a version 1 inode")?;
+    if v1.double_indirect_block != 0xDEAD || v1.uid != 0 || v1.mtime != 0 {
+        return Err("version 1 inode parsed wrong");
+    }
+    if Inode::from_bytes(&bytes[..Inode::V1_SIZE - 1]).is_ok() {
+        return Err("short inode was accepted");
+    }
+    if Inode::disk_size(1) != 120 || Inode::disk_size(2) != 128 || Inode::disk_size(3) != 144 {
+        return Err("inode stride does not match the superblock version");
+    }
+
+    Ok(())
+}
+
+fn check_ahci_lba48() -> Result<(), &'static str> {
+    let Some(port) = (0..32).find(|&port| {
+        ahci::ahci_port_identify(port).is_some_and(|identify| identify.sectors > LBA48_TEST_SECTOR)
+    }) else {
+        println!("No AHCI drive past the 28-bit limit, LBA48 check skipped");
+        return Ok(());
+    };
+
+    let hba_port = ahci::ahci_port(port).ok_or("AHCI port vanished")?;
+    let (phys, virt) = ahci::ahci_sector_buffer(hba_port).ok_or("no AHCI sector buffer")?;
+    let sector = unsafe { core::slice::from_raw_parts_mut(virt, 512) };
+
+    // Keep what was there so the check leaves the disk as it found it
+    ahci::ahci_read(hba_port, LBA48_TEST_SECTOR, 1, phys as *mut u8).map_err(|_| "read past the 28-bit limit failed")?;
+    let original = sector.to_vec();
+
+    // The pattern includes the LBA, so a sector written with the upper bytes dropped won't match
+    let pattern: Vec<u8> = (0..512).map(|i| (i as u8) ^ (LBA48_TEST_SECTOR >> (8 * (i % 8))) as u8 ^ 0xA5).collect();
+    sector.copy_from_slice(&pattern);
+    ahci::ahci_write(hba_port, LBA48_TEST_SECTOR, 1, phys as *const u8).map_err(|_| "write past the 28-bit limit failed")?;
+
+    sector.fill(0);
+    let result = (|| {
+        ahci::ahci_read(hba_port, LBA48_TEST_SECTOR, 1, phys as *mut u8).map_err(|_| "read past the 28-bit limit failed")?;
+        if *sector != *pattern {
+            return Err("sector past the 28-bit limit read back wrong");
+        }
+        // With the upper LBA bytes dropped the write would have landed on sector 0
+        ahci::ahci_read(hba_port, 0, 1, phys as *mut u8).map_err(|_| "failed to read sector 0")?;
+        if *sector == *pattern {
+            return Err("LBA48 write landed on sector 0");
+        }
+        Ok(())
+    })();
+
+    sector.copy_from_slice(&original);
+    ahci::ahci_write(hba_port, LBA48_TEST_SECTOR, 1, phys as *const u8).map_err(|_| "failed to restore the test sector")?;
+    result
+}
+
+fn check_filesystem() -> Result<(), &'static str> {
+    const TEST_DATA: &[u8] = b"Hello from EclipseOS!";
+
+    let drive: u8 = cmdline::cmdline_get("fsdrive").and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_FS_DRIVE);
+    if ide::ide_get_drive(drive as usize).is_none() {
+        return Err("fsdrive does not name a detected drive");
+    }
+    write_eclipse_fs(drive);
+
+    let super_block = SuperBlock::read_super_block(drive)?;
+
+    let mut sb_bytes = super_block.to_bytes();
+    SuperBlock::from_bytes(&sb_bytes).map_err(|_| "superblock round trip failed")?;
+    sb_bytes[20] ^= 0x01;
+    if SuperBlock::from_bytes(&sb_bytes).is_ok() {
+        return Err("corrupted superblock passed its checksum");
+    }
+
+    // Versions before the checksum have zeros where it goes and still have to mount
+    sb_bytes[20] ^= 0x01;
+    sb_bytes[104..108].fill(0);
+    for version in 1..=3 {
+        sb_bytes[2] = version;
+        let legacy = SuperBlock::from_bytes(&sb_bytes).map_err(|_| "superblock without a checksum did not mount")?;
+        if legacy.inode_size() != Inode::disk_size(version) as u64 {
+            return Err("legacy superblock uses the wrong inode size");
+        }
+    }
+    let bitmap = BlockBitmap::from_disk(drive as usize, &super_block).map_err(|_| "failed to load bitmap")?;
+    bitmap.verify(&super_block).map_err(|_| "bitmap is inconsistent with the superblock")?;
+    let mut inode_manager = InodeManager::new(drive as usize, super_block, bitmap)
+        .map_err(|_| "failed to initialize inode manager")?;
+
+    // Formatting has to leave an empty root directory behind
+    let root = DirectoryManager::root(&inode_manager).map_err(|_| "no root directory after format")?;
+    let root_inode = inode_manager.read_inode(root).map_err(|_| "failed to read root inode")?;
+    if root_inode.direct_blocks[0] == 0 || !inode_manager.bitmap.is_allocated(root_inode.direct_blocks[0] as usize) {
+        return Err("root directory block is not allocated");
+    }
+    let entries = DirectoryManager::list_directory(&inode_manager, root).map_err(|_| "failed to list root directory")?;
+    if !entries.is_empty() {
+        return Err("root directory is not empty after format");
+    }
+
+    let inode = create_file(&mut inode_manager, TEST_DATA).map_err(|_| "failed to create file")?;
+    let data = read_file(&inode_manager, inode).map_err(|_| "failed to read file")?;
+    if data != TEST_DATA {
+        return Err("file read-back mismatch");
+    }
+
+    // Entries go through the root's block and leave it behind once it is empty again
+    DirectoryManager::add_entry(&mut inode_manager, root, b"hello.txt", inode).map_err(|_| "failed to add directory entry")?;
+    if DirectoryManager::add_entry(&mut inode_manager, root, b"hello.txt", inode).is_ok() {
+        return Err("duplicate directory entry was accepted");
+    }
+    if DirectoryManager::find_entry(&inode_manager, root, b"hello.txt").map_err(|_| "failed to search root")? != Some(inode) {
+        return Err("added entry not found");
+    }
+    DirectoryManager::rename_entry(&mut inode_manager, root, b"hello.txt", b"greeting.txt").map_err(|_| "failed to rename entry")?;
+    if DirectoryManager::find_entry(&inode_manager, root, b"hello.txt").map_err(|_| "failed to search root")?.is_some() {
+        return Err("old name still present after rename");
+    }
+    if DirectoryManager::find_entry(&inode_manager, root, b"greeting.txt").map_err(|_| "failed to search root")? != Some(inode) {
+        return Err("renamed entry not found");
+    }
+    DirectoryManager::remove_entry(&mut inode_manager, root, b"greeting.txt").map_err(|_| "failed to remove entry")?;
+    if !DirectoryManager::list_directory(&inode_manager, root).map_err(|_| "failed to list root directory")?.is_empty() {
+        return Err("root directory not empty after remove");
+    }
+    let root_inode = inode_manager.read_inode(root).map_err(|_| "failed to read root inode")?;
+    if root_inode.direct_blocks[0] == 0 {
+        return Err("root directory lost its block after remove");
+    }
+    DirectoryManager::add_entry(&mut inode_manager, root, b"again.txt", inode).map_err(|_| "root unusable after remove")?;
+    DirectoryManager::remove_entry(&mut inode_manager, root, b"again.txt").map_err(|_| "failed to remove entry")?;
+
+    // Timestamps have one second resolution, so let the clock move on before writing again
+    let created = inode_manager.read_inode(inode).map_err(|_| "failed to read inode")?.mtime;
+    let deadline = time::get_time_ms() + MTIME_WAIT_MS;
+    while time::unix_time() <= created && time::get_time_ms() < deadline {
+        unsafe { core::arch::asm!("hlt") };
+    }
+
+    // Appending has to fill the partly used last block, then spill into the indirect block
+    let tail: Vec<u8> = (0..64 * 1024).map(|i| (i % 253) as u8).collect();
+    append(&mut inode_manager, inode, &tail).map_err(|_| "failed to append to file")?;
+    let modified = inode_manager.read_inode(inode).map_err(|_| "failed to read inode")?.mtime;
+    if modified <= created {
+        return Err("mtime did not advance after a write");
+    }
+    let data = read_file(&inode_manager, inode).map_err(|_| "failed to read appended file")?;
+    if data[..TEST_DATA.len()] != *TEST_DATA || data[TEST_DATA.len()..] != *tail {
+        return Err("appended file read-back mismatch");
+    }
+
+    let free_before = inode_manager.bitmap.free_blocks();
+    truncate(&mut inode_manager, inode, TEST_DATA.len() as u64).map_err(|_| "failed to truncate file")?;
+    let data = read_file(&inode_manager, inode).map_err(|_| "failed to read truncated file")?;
+    if data != TEST_DATA {
+        return Err("truncated file read-back mismatch");
+    }
+    if inode_manager.bitmap.free_blocks() <= free_before {
+        return Err("truncate did not free any blocks");
+    }
+
+    delete_file(&mut inode_manager, inode).map_err(|_| "failed to delete file")?;
+
+    // 64 KiB spans more than the 12 direct blocks, so this goes through the indirect block
+    let large: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    let inode = create_file(&mut inode_manager, &large).map_err(|_| "failed to create large file")?;
+    let data = read_file(&inode_manager, inode).map_err(|_| "failed to read large file")?;
+    if data != large {
+        return Err("large file read-back mismatch");
+    }
+
+    delete_file(&mut inode_manager, inode).map_err(|_| "failed to delete large file")?;
+
+    // A subdirectory linked from the root, holding one file
+    let dir = DirectoryManager::create_directory(&mut inode_manager).map_err(|_| "failed to create directory")?;
+    DirectoryManager::add_entry(&mut inode_manager, root, b"docs", dir).map_err(|_| "failed to link directory")?;
+    let file = create_file(&mut inode_manager, TEST_DATA).map_err(|_| "failed to create file")?;
+    DirectoryManager::add_entry(&mut inode_manager, dir, b"readme.txt", file).map_err(|_| "failed to add file to directory")?;
+
+    if DirectoryManager::find_entry(&inode_manager, root, b"docs").map_err(|_| "failed to search root")? != Some(dir) {
+        return Err("directory not found in root");
+    }
+    let found = DirectoryManager::find_entry(&inode_manager, dir, b"readme.txt").map_err(|_| "failed to search directory")?;
+    let found = found.ok_or("file not found in directory")?;
+    if read_file(&inode_manager, found).map_err(|_| "failed to read file through directory")? != TEST_DATA {
+        return Err("file read through directory mismatch");
+    }
+    let listing = DirectoryManager::list_directory(&inode_manager, dir).map_err(|_| "failed to list directory")?;
+    if listing.len() != 1 || listing[0].0 != file || listing[0].1 != b"readme.txt" {
+        return Err("directory listing mismatch");
+    }
+
+    DirectoryManager::remove_entry(&mut inode_manager, dir, b"readme.txt").map_err(|_| "failed to remove file entry")?;
+    delete_file(&mut inode_manager, file).map_err(|_| "failed to delete file")?;
+    DirectoryManager::remove_entry(&mut inode_manager, root, b"docs").map_err(|_| "failed to remove directory entry")?;
+    delete_file(&mut inode_manager, dir).map_err(|_| "failed to delete directory")?;
+
+    // A 1x1 24-bit BMP drawn from disk, then a file that isn't one
+    let mut bmp = alloc::vec![0u8; 58];
+    bmp[0..2].copy_from_slice(b"BM");
+    bmp[2..6].copy_from_slice(&58u32.to_le_bytes());
+    bmp[10..14].copy_from_slice(&54u32.to_le_bytes());
+    bmp[14..18].copy_from_slice(&40u32.to_le_bytes());
+    bmp[18..22].copy_from_slice(&1u32.to_le_bytes());
+    bmp[22..26].copy_from_slice(&1u32.to_le_bytes());
+    bmp[26..28].copy_from_slice(&1u16.to_le_bytes());
+    bmp[28..30].copy_from_slice(&24u16.to_le_bytes());
+    let image = create_file(&mut inode_manager, &bmp).map_err(|_| "failed to create image")?;
+    DirectoryManager::add_entry(&mut inode_manager, root, b"dot.bmp", image).map_err(|_| "failed to add image")?;
+    crate::image::show_bmp(&inode_manager, root, b"dot.bmp", 0, 0).map_err(|_| "failed to draw image from disk")?;
+    truncate(&mut inode_manager, image, 2).map_err(|_| "failed to truncate image")?;
+    if crate::image::show_bmp(&inode_manager, root, b"dot.bmp", 0, 0).is_ok() {
+        return Err("truncated image was drawn");
+    }
+    DirectoryManager::remove_entry(&mut inode_manager, root, b"dot.bmp").map_err(|_| "failed to remove image entry")?;
+    delete_file(&mut inode_manager, image).map_err(|_| "failed to delete image")?;
+    Ok(())
+}
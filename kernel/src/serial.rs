@@ -0,0 +1,175 @@
+//! 16550 serial ports
+//! Output talks to the UART straight through port I/O without any lock, so it still works
+//! when the thing that crashed was holding the console. COM1 input is polled or queued from IRQ 4.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use bare_x86_64::{inb, outb};
+
+use crate::idt::register_irq;
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const COM3: u16 = 0x3E8;
+pub const COM4: u16 = 0x2E8;
+
+// 16550 registers, offsets from the base port
+const UART_DATA: u16 = 0;
+const UART_INT_ENABLE: u16 = 1;
+const UART_FIFO_CTRL: u16 = 2;
+const UART_LINE_CTRL: u16 = 3;
+const UART_MODEM_CTRL: u16 = 4;
+const UART_LINE_STATUS: u16 = 5;
+const UART_SCRATCH: u16 = 7;
+
+const UART_LSR_DATA_READY: u8 = 1 << 0;
+const UART_LSR_THR_EMPTY: u8 = 1 << 5;
+const UART_IER_RX_AVAILABLE: u8 = 1 << 0;
+
+/// COM1 is wired to IRQ 4 on the primary PIC
+const COM1_IRQ: u8 = 4;
+
+/// Bytes received by the interrupt handler and not read yet, one slot is kept empty
+const RX_QUEUE_SIZE: usize = 256;
+static mut RX_QUEUE: [u8; RX_QUEUE_SIZE] = [0; RX_QUEUE_SIZE];
+static RX_HEAD: AtomicUsize = AtomicUsize::new(0);
+static RX_TAIL: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives up on a byte if the UART never becomes ready, e.g. when there is no serial port
+const UART_TX_TIMEOUT: usize = 100_000;
+
+/// The port behind `serial_init`, `panic_serial_write` and `read_byte`
+const DEFAULT_PORT: SerialPort = SerialPort::new(COM1);
+
+/// A 16550 UART at a fixed I/O base. It holds no state, so copies can be used from anywhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Checks for a UART by writing and reading back the scratch register.
+    pub fn is_present(&self) -> bool {
+        for pattern in [0x55u8, 0xAA] {
+            outb!(self.base + UART_SCRATCH, pattern);
+            if inb!(self.base + UART_SCRATCH) != pattern {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Sets the port to 38400 baud, 8N1 with FIFOs enabled.
+    /// Returns false and leaves the port alone if no UART answers at the base address.
+    pub fn init(&self) -> bool {
+        if !self.is_present() {
+            return false;
+        }
+
+        outb!(self.base + UART_INT_ENABLE, 0x00);
+        outb!(self.base + UART_LINE_CTRL, 0x80);
+        outb!(self.base + UART_DATA, 0x03);
+        outb!(self.base + UART_INT_ENABLE, 0x00);
+        outb!(self.base + UART_LINE_CTRL, 0x03);
+        outb!(self.base + UART_FIFO_CTRL, 0xC7);
+        outb!(self.base + UART_MODEM_CTRL, 0x0B);
+        true
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        for _ in 0..UART_TX_TIMEOUT {
+            if inb!(self.base + UART_LINE_STATUS) & UART_LSR_THR_EMPTY != 0 {
+                outb!(self.base + UART_DATA, byte);
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Writes `s`, turning `\n` into `\r\n`.
+    pub fn write_str(&self, s: &str) {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+
+    /// Polls for a received byte, `None` if nothing is waiting.
+    pub fn read_byte(&self) -> Option<u8> {
+        if inb!(self.base + UART_LINE_STATUS) & UART_LSR_DATA_READY != 0 {
+            Some(inb!(self.base + UART_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        SerialPort::write_str(self, s);
+        Ok(())
+    }
+}
+
+/// Sets up COM1, does nothing if it isn't there.
+pub fn serial_init() {
+    DEFAULT_PORT.init();
+}
+
+/// Writes `s` to COM1 without taking any lock, safe to call from panic and fault handlers.
+pub fn panic_serial_write(s: &str) {
+    DEFAULT_PORT.write_str(s);
+}
+
+/// `fmt::Write` front end for `panic_serial_write`.
+pub struct PanicSerialWriter;
+
+impl fmt::Write for PanicSerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        panic_serial_write(s);
+        Ok(())
+    }
+}
+
+/// Next byte received on COM1, `None` if nothing is waiting.
+/// Bytes queued by the interrupt handler come first, then the UART is polled directly.
+pub fn read_byte() -> Option<u8> {
+    let tail = RX_TAIL.load(Ordering::Acquire);
+    if tail != RX_HEAD.load(Ordering::Acquire) {
+        let byte = unsafe { RX_QUEUE[tail] };
+        RX_TAIL.store((tail + 1) % RX_QUEUE_SIZE, Ordering::Release);
+        return Some(byte);
+    }
+
+    DEFAULT_PORT.read_byte()
+}
+
+/// Enables the COM1 receive interrupt and unmasks IRQ 4, received bytes are then
+/// queued for `read_byte`.
+pub fn init_interrupt() {
+    register_irq(COM1_IRQ, serial_irq_handler);
+    outb!(COM1 + UART_INT_ENABLE, UART_IER_RX_AVAILABLE);
+}
+
+/// Called from the IRQ 4 handler, moves every byte COM1 holds into the queue.
+/// Bytes arriving while the queue is full are dropped.
+pub fn serial_irq_handler() {
+    while let Some(byte) = DEFAULT_PORT.read_byte() {
+        let head = RX_HEAD.load(Ordering::Acquire);
+        let next = (head + 1) % RX_QUEUE_SIZE;
+        if next != RX_TAIL.load(Ordering::Acquire) {
+            unsafe { RX_QUEUE[head] = byte };
+            RX_HEAD.store(next, Ordering::Release);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Kernel time keeping
+//! The PIT timer interrupt calls `tick` on every fire, everything else reads the counter
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use bare_x86_64::{io_wait, outb};
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Input clock of the 8253/8254 PIT
+const PIT_BASE_HZ: u32 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator)
+const PIT_CMD_CH0_RATE: u8 = 0x34;
+
+pub const DEFAULT_PIT_HZ: u32 = 1000;
+
+/// Until `set_pit_frequency` runs the PIT fires at its power-on rate of ~18.2 Hz
+static PIT_HZ: AtomicU32 = AtomicU32::new(18);
+
+/// Programs PIT channel 0 to fire about `hz` times a second.
+/// The divisor is `1193182 / hz` rounded down and clamped to 1..=65536, so the
+/// real rate can be a little off; the rate actually achieved is returned and used for all time math.
+pub fn set_pit_frequency(hz: u32) -> u32 {
+    let divisor = (PIT_BASE_HZ / hz.max(1)).clamp(1, 65536);
+    let actual_hz = PIT_BASE_HZ / divisor;
+
+    // A reload value of 0 means 65536
+    let reload = divisor as u16;
+    outb!(PIT_COMMAND, PIT_CMD_CH0_RATE);
+    outb!(PIT_CHANNEL0, reload as u8);
+    outb!(PIT_CHANNEL0, (reload >> 8) as u8);
+
+    PIT_HZ.store(actual_hz, Ordering::Relaxed);
+    actual_hz
+}
+
+/// Rate the timer interrupt currently fires at.
+pub fn pit_frequency() -> u32 {
+    PIT_HZ.load(Ordering::Relaxed)
+}
+
+/// Advances the tick counter, called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer ticks since interrupts were enabled.
+pub fn get_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since interrupts were enabled.
+pub fn get_time_ms() -> u64 {
+    (get_ticks() as u128 * 1000 / pit_frequency() as u128) as u64
+}
+
+/// Whole seconds since interrupts were enabled.
+pub fn get_uptime_seconds() -> u64 {
+    get_ticks() / pit_frequency() as u64
+}
+
+/// TSC increments per second, 0 until `calibrate_tsc` has run
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Timer ticks the TSC is measured over
+const TSC_CALIBRATION_TICKS: u64 = 10;
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measures the TSC rate against the timer interrupt. Needs interrupts on and the
+/// PIT programmed. Returns the rate in Hz, which `get_cpu_frequency_hz` reports from then on.
+pub fn calibrate_tsc() -> u64 {
+    let start = get_ticks();
+    while get_ticks() == start {
+        core::hint::spin_loop();
+    }
+
+    let begin_ticks = get_ticks();
+    let begin_tsc = rdtsc();
+    while get_ticks() - begin_ticks < TSC_CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let cycles = rdtsc() - begin_tsc;
+
+    let hz = cycles * pit_frequency() as u64 / TSC_CALIBRATION_TICKS;
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    hz
+}
+
+/// TSC rate found by `calibrate_tsc`, 0 if it hasn't run.
+pub fn get_cpu_frequency_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Busy-waits at least `ns` nanoseconds on the TSC. Before calibration every
+/// microsecond is approximated by a write to port 0x80, which takes about that long.
+pub fn delay_ns(ns: u64) {
+    let hz = get_cpu_frequency_hz();
+    if hz == 0 {
+        for _ in 0..ns.div_ceil(1000) {
+            io_wait!();
+        }
+        return;
+    }
+
+    let cycles = (ns as u128 * hz as u128).div_ceil(1_000_000_000) as u64;
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Busy-waits at least `us` microseconds, see `delay_ns`.
+pub fn delay_us(us: u64) {
+    delay_ns(us.saturating_mul(1000));
+}
+
+/// Unix time at the moment interrupts were enabled, set from the RTC
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Records the wall clock time the tick counter started from.
+pub fn set_boot_time(unix_seconds: u64) {
+    BOOT_UNIX_TIME.store(unix_seconds, Ordering::Relaxed);
+}
+
+/// Seconds since the Unix epoch, kept by the tick counter after boot instead of rereading the RTC.
+pub fn unix_time() -> u64 {
+    BOOT_UNIX_TIME.load(Ordering::Relaxed) + get_uptime_seconds()
+}
+
+/// Nanoseconds covered by `ticks` timer ticks.
+fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / pit_frequency() as u128) as u64
+}
+
+/// A point on the monotonic tick clock, only as precise as one tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Self { ticks: get_ticks() }
+    }
+
+    /// Nanoseconds since this instant was taken.
+    pub fn elapsed(&self) -> u64 {
+        ticks_to_ns(get_ticks().saturating_sub(self.ticks))
+    }
+
+    /// Nanoseconds from `earlier` to this instant, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> u64 {
+        ticks_to_ns(self.ticks.saturating_sub(earlier.ticks))
+    }
+}
@@ -5,8 +5,11 @@
 
 extern crate alloc;
 
+use alloc::vec::Vec;
+
 use bare_x86_64::{outl, inl};
 use eclipse_framebuffer::println;
+use spin::{Mutex, MutexGuard};
 
 // PCI Configuration Space I/O Ports
 const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
@@ -19,15 +22,33 @@ const MAX_PCI_DEVICES: usize = 256;
 const PCI_VENDOR_ID: u8 = 0x00;
 const PCI_DEVICE_ID: u8 = 0x02;
 const PCI_COMMAND: u8 = 0x04;
+const PCI_STATUS: u8 = 0x06;
 const PCI_CLASS_CODE: u8 = 0x0B;
 const PCI_SUBCLASS: u8 = 0x0A;
 const PCI_PROG_IF: u8 = 0x09;
 const PCI_HEADER_TYPE: u8 = 0x0E;
 const PCI_BAR0: u8 = 0x10;
 const PCI_SECONDARY_BUS: u8 = 0x19;
+const PCI_CAPABILITIES_PTR: u8 = 0x34;
 const PCI_INTERRUPT_LINE: u8 = 0x3C;
 const PCI_INTERRUPT_PIN: u8 = 0x3D;
 
+// PCI Header Type Register
+const PCI_HEADER_MULTIFUNCTION: u8 = 0x80;
+const PCI_HEADER_LAYOUT_MASK: u8 = 0x7F;
+const PCI_HEADER_LAYOUT_PCI_BRIDGE: u8 = 0x01;
+const PCI_HEADER_LAYOUT_CARDBUS_BRIDGE: u8 = 0x02;
+
+// PCI Status Register
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+// Upper bound on capabilities so a malformed list can't loop forever
+const PCI_MAX_CAPABILITIES: usize = 48;
+
+// PCI Capability IDs
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
 // PCI Class Codes
 const PCI_CLASS_BRIDGE: u8 = 0x06;
 const PCI_SUBCLASS_PCI_BRIDGE: u8 = 0x04;
@@ -50,6 +71,7 @@ pub struct PCIDevice {
     pub prog_if: u8,
     pub bar: [u32; 6],
     pub interrupt_line: u8,
+    pub header_type: u8,
 }
 
 impl PCIDevice {
@@ -65,35 +87,63 @@ impl PCIDevice {
             prog_if: 0,
             bar: [0; 6],
             interrupt_line: 0,
+            header_type: 0,
         }
     }
+
+    /// Function 0 of a multifunction device, only meaningful on function 0.
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & PCI_HEADER_MULTIFUNCTION != 0
+    }
+
+    /// PCI-to-PCI or CardBus bridge rather than an endpoint.
+    pub fn is_bridge(&self) -> bool {
+        matches!(
+            self.header_type & PCI_HEADER_LAYOUT_MASK,
+            PCI_HEADER_LAYOUT_PCI_BRIDGE | PCI_HEADER_LAYOUT_CARDBUS_BRIDGE
+        )
+    }
 }
 
 static mut PCI_DEVICES: [PCIDevice; MAX_PCI_DEVICES] = [PCIDevice::new(); MAX_PCI_DEVICES];
 static mut PCI_DEVICE_COUNT: u32 = 0;
 
-pub fn pci_config_read_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
-    let address: u32 = ((bus as u32) << 16) 
+/// Serializes access to the 0xCF8/0xCFC pair so the address latch and the data access can't interleave
+static PCI_CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Takes the config space lock, hold it across a read-modify-write to keep it atomic.
+pub fn pci_config_lock() -> MutexGuard<'static, ()> {
+    PCI_CONFIG_LOCK.lock()
+}
+
+fn pci_config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    ((bus as u32) << 16) 
         | ((device as u32) << 11)
         | ((function as u32) << 8) 
         | ((offset as u32) & 0xFC) 
-        | 0x80000000;
-    
-    outl!(PCI_CONFIG_ADDRESS, address);
+        | 0x80000000
+}
+
+fn pci_config_read_dword_locked(_guard: &MutexGuard<'static, ()>, bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    outl!(PCI_CONFIG_ADDRESS, pci_config_address(bus, device, function, offset));
     inl!(PCI_CONFIG_DATA)
 }
 
-pub fn pci_config_write_dword(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
-    let address: u32 = ((bus as u32) << 16) 
-        | ((device as u32) << 11)
-        | ((function as u32) << 8) 
-        | ((offset as u32) & 0xFC) 
-        | 0x80000000;
-    
-    outl!(PCI_CONFIG_ADDRESS, address);
+fn pci_config_write_dword_locked(_guard: &MutexGuard<'static, ()>, bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    outl!(PCI_CONFIG_ADDRESS, pci_config_address(bus, device, function, offset));
     outl!(PCI_CONFIG_DATA, value);
 }
 
+pub fn pci_config_read_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let guard = pci_config_lock();
+    pci_config_read_dword_locked(&guard, bus, device, function, offset)
+}
+
+pub fn pci_config_write_dword(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let guard = pci_config_lock();
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, value);
+}
+
 pub fn pci_config_read_word(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
     let data: u32 = pci_config_read_dword(bus, device, function, offset);
     let shift: u8 = if (offset & 2) != 0 { 16 } else { 0 };
@@ -107,19 +157,21 @@ pub fn pci_config_read_byte(bus: u8, device: u8, function: u8, offset: u8) -> u8
 }
 
 pub fn pci_config_write_word(bus: u8, device: u8, function: u8, offset: u8, value: u16) {
-    let mut data: u32 = pci_config_read_dword(bus, device, function, offset);
+    let guard = pci_config_lock();
+    let mut data: u32 = pci_config_read_dword_locked(&guard, bus, device, function, offset);
     let shift: u8 = (offset & 2) * 8;
     data &= !(0xFFFF << shift);
     data |= (value as u32) << shift;
-    pci_config_write_dword(bus, device, function, offset, data);
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, data);
 }
 
 pub fn pci_config_write_byte(bus: u8, device: u8, function: u8, offset: u8, value: u8) {
-    let mut data: u32 = pci_config_read_dword(bus, device, function, offset);
+    let guard = pci_config_lock();
+    let mut data: u32 = pci_config_read_dword_locked(&guard, bus, device, function, offset);
     let shift: u8 = (offset & 3) * 8;
     data &= !(0xFF << shift);
     data |= (value as u32) << shift;
-    pci_config_write_dword(bus, device, function, offset, data);
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, data);
 }
 
 pub fn get_vendor_id(bus: u8, device: u8, function: u8) -> u16 {
@@ -139,10 +191,13 @@ pub fn pci_write_bar(bus: u8, device: u8, function: u8, bar_num: u8, value: u32)
 }
 
 pub fn pci_get_bar_size(bus: u8, device: u8, function: u8, bar_num: u8) -> u32 {
-    let original = pci_read_bar(bus, device, function, bar_num);
-    pci_write_bar(bus, device, function, bar_num, 0xFFFFFFFF);
-    let mut size = pci_read_bar(bus, device, function, bar_num);
-    pci_write_bar(bus, device, function, bar_num, original);
+    let guard = pci_config_lock();
+    let offset = PCI_BAR0 + (bar_num * 4);
+    let original = pci_config_read_dword_locked(&guard, bus, device, function, offset);
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, 0xFFFFFFFF);
+    let mut size = pci_config_read_dword_locked(&guard, bus, device, function, offset);
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, original);
+    drop(guard);
     
     if (original & 0x1) != 0 {
         size &= 0xFFFFFFFC;
@@ -153,6 +208,116 @@ pub fn pci_get_bar_size(bus: u8, device: u8, function: u8, bar_num: u8) -> u32 {
     (!size).wrapping_add(1)
 }
 
+/// A decoded base address register
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub base: u64,
+    pub size: u64,
+    pub is_io: bool,
+    pub prefetchable: bool,
+}
+
+/// Decodes a BAR including 64-bit memory BARs that take up `bar_num` and `bar_num + 1`.
+/// Returns `None` for an unimplemented BAR or a 64-bit BAR in the last slot.
+pub fn pci_decode_bar(bus: u8, device: u8, function: u8, bar_num: u8) -> Option<Bar> {
+    if bar_num > 5 {
+        return None;
+    }
+
+    let guard = pci_config_lock();
+    let offset = PCI_BAR0 + (bar_num * 4);
+    let low = pci_config_read_dword_locked(&guard, bus, device, function, offset);
+
+    if (low & 0x1) != 0 {
+        pci_config_write_dword_locked(&guard, bus, device, function, offset, 0xFFFFFFFF);
+        let mask = pci_config_read_dword_locked(&guard, bus, device, function, offset) & 0xFFFFFFFC;
+        pci_config_write_dword_locked(&guard, bus, device, function, offset, low);
+        if mask == 0 {
+            return None;
+        }
+
+        return Some(Bar {
+            base: (low & 0xFFFFFFFC) as u64,
+            size: ((!mask).wrapping_add(1) & 0xFFFF) as u64,
+            is_io: true,
+            prefetchable: false,
+        });
+    }
+
+    let prefetchable = (low & 0x8) != 0;
+    let is_64bit = ((low >> 1) & 0x3) == 0x2;
+
+    if is_64bit {
+        if bar_num == 5 {
+            return None;
+        }
+
+        let high_offset = offset + 4;
+        let high = pci_config_read_dword_locked(&guard, bus, device, function, high_offset);
+
+        pci_config_write_dword_locked(&guard, bus, device, function, offset, 0xFFFFFFFF);
+        pci_config_write_dword_locked(&guard, bus, device, function, high_offset, 0xFFFFFFFF);
+        let mask_low = pci_config_read_dword_locked(&guard, bus, device, function, offset) & 0xFFFFFFF0;
+        let mask_high = pci_config_read_dword_locked(&guard, bus, device, function, high_offset);
+        pci_config_write_dword_locked(&guard, bus, device, function, offset, low);
+        pci_config_write_dword_locked(&guard, bus, device, function, high_offset, high);
+
+        let mask = ((mask_high as u64) << 32) | mask_low as u64;
+        if mask == 0 {
+            return None;
+        }
+
+        return Some(Bar {
+            base: ((high as u64) << 32) | (low & 0xFFFFFFF0) as u64,
+            size: (!mask).wrapping_add(1),
+            is_io: false,
+            prefetchable,
+        });
+    }
+
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, 0xFFFFFFFF);
+    let mask = pci_config_read_dword_locked(&guard, bus, device, function, offset) & 0xFFFFFFF0;
+    pci_config_write_dword_locked(&guard, bus, device, function, offset, low);
+    if mask == 0 {
+        return None;
+    }
+
+    Some(Bar {
+        base: (low & 0xFFFFFFF0) as u64,
+        size: (!mask).wrapping_add(1) as u64,
+        is_io: false,
+        prefetchable,
+    })
+}
+
+/// Walks the capability list and returns `(capability_id, offset)` pairs in list order.
+pub fn pci_capabilities(bus: u8, device: u8, function: u8) -> Vec<(u8, u8)> {
+    let mut capabilities = Vec::new();
+
+    let status = pci_config_read_word(bus, device, function, PCI_STATUS);
+    if (status & PCI_STATUS_CAP_LIST) == 0 {
+        return capabilities;
+    }
+
+    // The low two bits of every pointer are reserved
+    let mut offset = pci_config_read_byte(bus, device, function, PCI_CAPABILITIES_PTR) & 0xFC;
+    while offset != 0 && capabilities.len() < PCI_MAX_CAPABILITIES {
+        let id = pci_config_read_byte(bus, device, function, offset);
+        capabilities.push((id, offset));
+        offset = pci_config_read_byte(bus, device, function, offset + 1) & 0xFC;
+    }
+
+    capabilities
+}
+
+/// Returns the config space offset of the first capability with id `cap_id`.
+pub fn pci_find_capability(bus: u8, device: u8, function: u8, cap_id: u8) -> Option<u8> {
+    pci_capabilities(bus, device, function)
+        .into_iter()
+        .find(|&(id, _)| id == cap_id)
+        .map(|(_, offset)| offset)
+}
+
 pub fn pci_enable_bus_master(bus: u8, device: u8, function: u8) {
     let mut command = pci_config_read_word(bus, device, function, PCI_COMMAND);
     command |= 0x04;
@@ -185,13 +350,29 @@ pub fn pci_get_interrupt_pin(bus: u8, device: u8, function: u8) -> u8 {
     pci_config_read_byte(bus, device, function, PCI_INTERRUPT_PIN)
 }
 
+/// Index of the table entry for bus/device/function, if it has been recorded.
+fn pci_find_slot(bus: u8, device: u8, function: u8) -> Option<usize> {
+    unsafe {
+        (0..PCI_DEVICE_COUNT as usize).find(|&i| {
+            PCI_DEVICES[i].bus == bus && PCI_DEVICES[i].device == device && PCI_DEVICES[i].function == function
+        })
+    }
+}
+
+/// Records a function in the device table. A function that is already listed has its
+/// entry refreshed instead of being added twice.
 pub fn pci_add_device(bus: u8, device: u8, function: u8) {
     unsafe {
-        if PCI_DEVICE_COUNT >= MAX_PCI_DEVICES as u32 {
-            return;
-        }
+        let index = match pci_find_slot(bus, device, function) {
+            Some(index) => index,
+            None if PCI_DEVICE_COUNT < MAX_PCI_DEVICES as u32 => {
+                PCI_DEVICE_COUNT += 1;
+                PCI_DEVICE_COUNT as usize - 1
+            }
+            None => return,
+        };
 
-        let dev = &mut PCI_DEVICES[PCI_DEVICE_COUNT as usize];
+        let dev = &mut PCI_DEVICES[index];
         dev.bus = bus;
         dev.device = device;
         dev.function = function;
@@ -201,15 +382,25 @@ pub fn pci_add_device(bus: u8, device: u8, function: u8) {
     dev.subclass = pci_config_read_byte(bus, device, function, PCI_SUBCLASS);
     dev.prog_if = pci_config_read_byte(bus, device, function, PCI_PROG_IF);
     dev.interrupt_line = pci_get_interrupt_line(bus, device, function);
+    dev.header_type = pci_config_read_byte(bus, device, function, PCI_HEADER_TYPE);
 
         for i in 0..6 {
             dev.bar[i] = pci_read_bar(bus, device, function, i as u8);
         }
-
-        PCI_DEVICE_COUNT += 1;
     }
 }
 
+/// Number of devices found by `check_all_buses`, 0 before enumeration.
+pub fn pci_device_count() -> usize {
+    unsafe { PCI_DEVICE_COUNT as usize }
+}
+
+/// Iterates over every enumerated device, yields nothing before `check_all_buses` has run.
+pub fn pci_devices() -> impl Iterator<Item = &'static PCIDevice> {
+    let devices = unsafe { &*core::ptr::addr_of!(PCI_DEVICES) };
+    devices[..pci_device_count()].iter()
+}
+
 pub fn pci_find_device(vendor_id: u16, device_id: u16) -> Option<&'static PCIDevice> {
     unsafe {
         for i in 0..PCI_DEVICE_COUNT as usize {
@@ -245,6 +436,38 @@ pub fn pci_find_class_prog_if(class_code: u8, subclass: u8, prog_if: u8) -> Opti
     None
 }
 
+/// Human readable name for a class/subclass/prog IF triple, covering the classes the OS
+/// cares about. Anything else is "Unknown", print the raw codes next to it.
+pub fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x01, 0x01, _) => "Mass Storage Controller / IDE",
+        (0x01, 0x05, _) => "Mass Storage Controller / ATA",
+        (0x01, 0x06, 0x01) => "Mass Storage Controller / SATA / AHCI",
+        (0x01, 0x06, _) => "Mass Storage Controller / SATA",
+        (0x01, 0x08, 0x02) => "Mass Storage Controller / NVM / NVMe",
+        (0x01, 0x08, _) => "Mass Storage Controller / NVM",
+        (0x01, _, _) => "Mass Storage Controller",
+        (0x02, 0x00, _) => "Network Controller / Ethernet",
+        (0x02, _, _) => "Network Controller",
+        (0x03, 0x00, _) => "Display Controller / VGA Compatible",
+        (0x03, _, _) => "Display Controller",
+        (0x04, _, _) => "Multimedia Controller",
+        (0x06, 0x00, _) => "Bridge / Host",
+        (0x06, 0x01, _) => "Bridge / ISA",
+        (0x06, 0x04, _) => "Bridge / PCI-to-PCI",
+        (0x06, 0x07, _) => "Bridge / CardBus",
+        (0x06, _, _) => "Bridge",
+        (0x0C, 0x03, 0x00) => "Serial Bus Controller / USB / UHCI",
+        (0x0C, 0x03, 0x10) => "Serial Bus Controller / USB / OHCI",
+        (0x0C, 0x03, 0x20) => "Serial Bus Controller / USB / EHCI",
+        (0x0C, 0x03, 0x30) => "Serial Bus Controller / USB / xHCI",
+        (0x0C, 0x03, _) => "Serial Bus Controller / USB",
+        (0x0C, 0x05, _) => "Serial Bus Controller / SMBus",
+        (0x0C, _, _) => "Serial Bus Controller",
+        _ => "Unknown",
+    }
+}
+
 pub fn check_function(bus: u8, device: u8, function: u8) {
     let vendor = get_vendor_id(bus, device, function);
     if vendor == 0xFFFF {
@@ -252,13 +475,14 @@ pub fn check_function(bus: u8, device: u8, function: u8) {
     }
 
     let device_id = get_device_id(bus, device, function);
-    println!("Found PCI device: Bus {:02x}, Device {:02x}, Func {:02x} => Vendor: {:04x}, Device: {:04x}",
-             bus, device, function, vendor, device_id);
-
-    pci_add_device(bus, device, function);
-
     let base_class = pci_config_read_byte(bus, device, function, PCI_CLASS_CODE);
     let sub_class = pci_config_read_byte(bus, device, function, PCI_SUBCLASS);
+    let prog_if = pci_config_read_byte(bus, device, function, PCI_PROG_IF);
+    println!("Found PCI device: Bus {:02x}, Device {:02x}, Func {:02x} => Vendor: {:04x}, Device: {:04x}, {} ({:02x}:{:02x}:{:02x})",
+             bus, device, function, vendor, device_id,
+             class_name(base_class, sub_class, prog_if), base_class, sub_class, prog_if);
+
+    pci_add_device(bus, device, function);
 
     if base_class == PCI_CLASS_BRIDGE && sub_class == PCI_SUBCLASS_PCI_BRIDGE {
         let secondary_bus = pci_config_read_byte(bus, device, function, PCI_SECONDARY_BUS);
@@ -266,6 +490,15 @@ pub fn check_function(bus: u8, device: u8, function: u8) {
     }
 }
 
+/// Uses the header type recorded for function 0, config space is only read again
+/// when the table was full and the device didn't make it in.
+fn is_multifunction(bus: u8, device: u8) -> bool {
+    match pci_find_slot(bus, device, 0) {
+        Some(index) => unsafe { PCI_DEVICES[index].is_multifunction() },
+        None => pci_config_read_byte(bus, device, 0, PCI_HEADER_TYPE) & PCI_HEADER_MULTIFUNCTION != 0,
+    }
+}
+
 pub fn check_device(bus: u8, device: u8) {
     let vendor = get_vendor_id(bus, device, 0);
     if vendor == 0xFFFF {
@@ -274,8 +507,7 @@ pub fn check_device(bus: u8, device: u8) {
 
     check_function(bus, device, 0);
 
-    let header_type = pci_config_read_byte(bus, device, 0, PCI_HEADER_TYPE);
-    if (header_type & 0x80) != 0 {
+    if is_multifunction(bus, device) {
         for function in 1..8 {
             if get_vendor_id(bus, device, function) != 0xFFFF {
                 check_function(bus, device, function);
@@ -290,9 +522,30 @@ pub fn check_bus(bus: u8) {
     }
 }
 
+/// Re-reads every function on `bus`, e.g. after a device was hot-plugged. Known functions
+/// are refreshed, new ones are appended while the table has room. Entries for devices that
+/// went away are kept, and bridges are not followed.
+pub fn pci_rescan_bus(bus: u8) {
+    for device in 0..32 {
+        if get_vendor_id(bus, device, 0) == 0xFFFF {
+            continue;
+        }
+
+        pci_add_device(bus, device, 0);
+
+        if is_multifunction(bus, device) {
+            for function in 1..8 {
+                if get_vendor_id(bus, device, function) != 0xFFFF {
+                    pci_add_device(bus, device, function);
+                }
+            }
+        }
+    }
+}
+
 pub fn check_all_buses() {
     let header_type = pci_config_read_byte(0, 0, 0, PCI_HEADER_TYPE);
-    if (header_type & 0x80) == 0 {
+    if (header_type & PCI_HEADER_MULTIFUNCTION) == 0 {
         check_bus(0);
     } else {
         for function in 0..8 {